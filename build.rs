@@ -3,5 +3,6 @@ fn main() {
     cfg_aliases::cfg_aliases! {
         dx12: { all(target_os = "windows", feature = "dx12") },
         vulkan: { all(not(target_arch = "wasm32"), feature = "vulkan") },
+        gl: { all(unix, not(target_arch = "wasm32"), feature = "gl") },
     }
 }
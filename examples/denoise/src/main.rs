@@ -1,94 +1,126 @@
 use std::{fs::File, sync::mpsc};
 
 use futures::executor::block_on;
-use image::{ImageBuffer, Rgb, buffer::ConvertBuffer};
+use image::{ImageBuffer, Rgba};
+use oidn_wgpu_interop::{BufferFormat, DenoiseInputs, DenoiserDescriptor, DenoiserSettings};
 use wgpu::{
-    Backends, BufferAddress, BufferUsages, Instance, InstanceDescriptor,
-    wgt::{BufferDescriptor, DeviceDescriptor, PollType},
+    BufferAddress, BufferUsages, COPY_BYTES_PER_ROW_ALIGNMENT, Extent3d, Instance,
+    InstanceDescriptor, PowerPreference, TexelCopyBufferInfo, TexelCopyBufferLayout,
+    TextureDimension, TextureFormat, TextureUsages,
+    util::align_to,
+    wgt::{BufferDescriptor, DeviceDescriptor, PollType, TextureDescriptor},
 };
 
 fn main() {
     let image = image::load_from_memory(include_bytes!("../box.png"))
         .unwrap()
-        .to_rgb32f();
+        .to_rgba8();
 
     // Set up the oidn shared device and wgpu queue
     let instance = Instance::new(InstanceDescriptor::new_without_display_handle());
 
-    let mut device_queue = None;
-
-    for adapter in block_on(instance.enumerate_adapters(Backends::all())) {
-        if let Ok(dev) = block_on(oidn_wgpu_interop::Device::new(
-            &adapter,
-            &DeviceDescriptor::default(),
-        )) {
-            device_queue = Some(dev);
-        }
-    }
-
     // A real implementation should probably fall back to cpu copying.
-    let (device, queue) = device_queue.expect("Failed to find an interoperability capable device");
-
-    let image_byte_size = size_of_val::<[f32]>(image.as_raw());
-
-    // Fake a real workload, just write image to buffer.
-    // This buffer would get the output of the ray tracer
-    let buffer = device.wgpu_device().create_buffer(&BufferDescriptor {
-        label: Some("renderer output buffer"),
-        size: image_byte_size as BufferAddress,
-        usage: BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
-        mapped_at_creation: false,
+    let selection = block_on(oidn_wgpu_interop::Device::new_best(
+        &instance,
+        &DeviceDescriptor::default(),
+        PowerPreference::HighPerformance,
+    ))
+    .expect("Failed to find an interoperability capable device");
+    let (device, queue) = (selection.device, selection.queue);
+
+    let (width, height) = image.dimensions();
+    let size = Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+
+    // Fake a real workload, just write image to a texture.
+    // This texture would get the output of the renderer
+    let texture = device.wgpu_device().create_texture(&TextureDescriptor {
+        label: Some("renderer output texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::Rgba8Unorm,
+        usage: TextureUsages::COPY_DST
+            | TextureUsages::COPY_SRC
+            | TextureUsages::TEXTURE_BINDING
+            | TextureUsages::STORAGE_BINDING,
+        view_formats: &[],
     });
-
-    let shared_buffer = device
-        .allocate_shared_buffers(image_byte_size as BufferAddress)
-        .unwrap();
-
-    queue.write_buffer(&buffer, 0, bytemuck::cast_slice(image.as_raw()));
-
-    let mut encoder = device
-        .wgpu_device()
-        .create_command_encoder(&Default::default());
-    encoder.copy_buffer_to_buffer(
-        &buffer,
-        0,
-        shared_buffer.wgpu_buffer(),
-        0,
-        image_byte_size as u64,
+    queue.write_texture(
+        texture.as_image_copy(),
+        image.as_raw(),
+        TexelCopyBufferLayout {
+            offset: 0,
+            bytes_per_row: Some(width * 4),
+            rows_per_image: None,
+        },
+        size,
     );
-    queue.submit([encoder.finish()]);
 
-    // setup filter
-    let mut filter = oidn::filter::RayTracing::new(device.oidn_device());
-    filter.image_dimensions(image.width() as usize, image.height() as usize);
-
-    // Must wait for wgpu to finish before we can start oidn workload.
-    device
-        .wgpu_device()
-        .poll(PollType::wait_indefinitely())
-        .unwrap();
-
-    // filter
-    filter
-        .filter_in_place_buffer(&shared_buffer.oidn_buffer())
+    // The denoiser owns the shared buffers and handles the copies and synchronisation.
+    let denoiser = device
+        .create_denoiser(&DenoiserDescriptor {
+            width,
+            height,
+            buffer_format: BufferFormat::Float3,
+            albedo: false,
+            normal: false,
+            settings: DenoiserSettings {
+                // The png is sRGB encoded.
+                srgb: true,
+                ..Default::default()
+            },
+            tile_size: None,
+        })
         .unwrap();
 
-    // Output to a wgpu buffer (in this case to be saved to disk). No sync needed here because oidn blocks the CPU until the workload is finished.
+    // Denoise the texture in place, then copy it to a wgpu buffer (in this case to be saved to
+    // disk).
+    let bytes_per_row = align_to(width * 4, COPY_BYTES_PER_ROW_ALIGNMENT);
     let out_buffer = device.wgpu_device().create_buffer(&BufferDescriptor {
         label: Some("save buffer"),
-        size: image_byte_size as BufferAddress,
+        size: BufferAddress::from(bytes_per_row * height),
         usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
         mapped_at_creation: false,
     });
     let mut encoder = device
         .wgpu_device()
         .create_command_encoder(&Default::default());
-    encoder.copy_buffer_to_buffer(
-        shared_buffer.wgpu_buffer(),
-        0,
-        &out_buffer,
-        0,
-        image_byte_size as u64,
+    denoiser
+        .encode_inputs(
+            &mut encoder,
+            &DenoiseInputs {
+                color: &texture,
+                albedo: None,
+                normal: None,
+            },
+        )
+        .unwrap();
+    let execution = denoiser
+        .execute_async(queue.submit([encoder.finish()]))
+        .unwrap();
+    // Waiting for wgpu and OIDN happens in the background, an interactive application would
+    // keep rendering here and poll the future once per frame.
+    block_on(execution).unwrap();
+    let mut encoder = device
+        .wgpu_device()
+        .create_command_encoder(&Default::default());
+    denoiser.encode_output(&mut encoder, &texture).unwrap();
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        TexelCopyBufferInfo {
+            buffer: &out_buffer,
+            layout: TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(bytes_per_row),
+                rows_per_image: None,
+            },
+        },
+        size,
     );
     let (send, recv) = mpsc::channel();
     encoder.map_buffer_on_submit(&out_buffer, wgpu::MapMode::Read, .., move |res| {
@@ -105,17 +137,15 @@ fn main() {
     recv.recv().unwrap();
 
     let mapped_range = out_buffer.get_mapped_range(..);
-    let image: ImageBuffer<Rgb<f32>, _> = ImageBuffer::from_raw(
-        image.width(),
-        image.height(),
-        bytemuck::cast_slice(&*mapped_range),
-    )
-    .unwrap();
+    let pixels: Vec<u8> = mapped_range
+        .chunks(bytes_per_row as usize)
+        .flat_map(|row| &row[..width as usize * 4])
+        .copied()
+        .collect();
+    let image: ImageBuffer<Rgba<u8>, _> = ImageBuffer::from_raw(width, height, pixels).unwrap();
 
     let mut file = File::create("./examples/denoise/box_denoised.png").unwrap();
-    ConvertBuffer::<ImageBuffer<Rgb<u8>, _>>::convert(&image)
-        .write_to(&mut file, image::ImageFormat::Png)
-        .unwrap();
+    image.write_to(&mut file, image::ImageFormat::Png).unwrap();
 
     drop(mapped_range);
     out_buffer.unmap();
@@ -0,0 +1,539 @@
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+
+use wgpu::util::{DeviceExt, align_to};
+use wgpu::{BufferAddress, BufferUsages, TextureFormat, TextureUsages};
+
+use crate::Device;
+
+const SHADER: &str = include_str!("convert.wgsl");
+
+/// Must match `WORKGROUP_SIZE` in the shader.
+const WORKGROUP_SIZE: u32 = 64;
+
+const HALF3: u32 = 1;
+const SRGB: u32 = 2;
+
+/// The storage formats [`Device::encode_buffer_to_texture`] can write, each needs its own
+/// pipeline.
+const DESTINATION_FORMATS: [TextureFormat; 3] = [
+    TextureFormat::Rgba8Unorm,
+    TextureFormat::Rgba16Float,
+    TextureFormat::Rgba32Float,
+];
+
+/// The layout of the pixels in a buffer OIDN reads as an image.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum BufferFormat {
+    /// Three tightly packed `f32`s per pixel.
+    #[default]
+    Float3,
+    /// Three tightly packed `f16`s per pixel.
+    Half3,
+}
+
+impl BufferFormat {
+    pub fn oidn_format(self) -> oidn::sys::OIDNFormat {
+        match self {
+            BufferFormat::Float3 => oidn::sys::OIDNFormat_OIDN_FORMAT_FLOAT3,
+            BufferFormat::Half3 => oidn::sys::OIDNFormat_OIDN_FORMAT_HALF3,
+        }
+    }
+
+    pub fn pixel_size(self) -> BufferAddress {
+        match self {
+            BufferFormat::Float3 => 12,
+            BufferFormat::Half3 => 6,
+        }
+    }
+
+    /// The size a buffer needs to hold a `width` by `height` image, rounded up to a multiple of
+    /// four because the conversion shaders write whole words.
+    pub fn buffer_size(self, width: u32, height: u32) -> BufferAddress {
+        let pixels = BufferAddress::from(width) * BufferAddress::from(height);
+        align_to(pixels * self.pixel_size(), 4)
+    }
+}
+
+/// How [`Device::encode_texture_to_buffer`] and [`Device::encode_buffer_to_texture`] convert
+/// colors.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct ConversionOptions {
+    pub buffer_format: BufferFormat,
+    /// Whether the texture holds sRGB encoded colors, which are decoded when read and encoded
+    /// when written. Textures with an sRGB format are always decoded by the GPU.
+    pub srgb: bool,
+    /// Multiplies the linear colors when converting into a buffer and divides them when
+    /// converting back, so that a round trip with the same options keeps them. Must be finite
+    /// and greater than zero.
+    pub exposure: f32,
+}
+
+impl ConversionOptions {
+    fn check(&self) -> Result<(), ConversionError> {
+        // converting back divides by the exposure
+        if !(self.exposure.is_finite() && self.exposure > 0.0) {
+            return Err(ConversionError::InvalidExposure(self.exposure));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        Self {
+            buffer_format: BufferFormat::Float3,
+            srgb: false,
+            exposure: 1.0,
+        }
+    }
+}
+
+pub enum ConversionError {
+    /// The texture is not a 2D texture with one sample per texel.
+    UnsupportedTexture,
+    /// The texture's format can't be converted in this direction.
+    UnsupportedFormat(TextureFormat),
+    /// The texture lacks usages the conversion needs.
+    MissingTextureUsage(TextureUsages),
+    /// The buffer lacks usages the conversion needs.
+    MissingBufferUsage(BufferUsages),
+    /// The buffer slice is smaller than [`BufferFormat::buffer_size`] of the texture.
+    BufferTooSmall {
+        required: BufferAddress,
+        actual: BufferAddress,
+    },
+    /// The buffer slice's offset is not a multiple of the device's
+    /// `min_storage_buffer_offset_alignment`.
+    UnalignedOffset {
+        offset: BufferAddress,
+        alignment: u32,
+    },
+    /// [`ConversionOptions::exposure`] is not a finite number greater than zero.
+    InvalidExposure(f32),
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConversionError::UnsupportedTexture => {
+                f.write_str("Only single sampled 2D textures can be converted")
+            }
+            ConversionError::UnsupportedFormat(format) => {
+                f.write_str("Can't convert textures with the format ")?;
+                Debug::fmt(format, f)
+            }
+            ConversionError::MissingTextureUsage(usage) => {
+                f.write_str("The texture is missing the usages ")?;
+                Debug::fmt(usage, f)
+            }
+            ConversionError::MissingBufferUsage(usage) => {
+                f.write_str("The buffer is missing the usages ")?;
+                Debug::fmt(usage, f)
+            }
+            ConversionError::BufferTooSmall { required, actual } => {
+                f.write_str("The buffer has ")?;
+                Display::fmt(actual, f)?;
+                f.write_str(" bytes but the image needs ")?;
+                Display::fmt(required, f)
+            }
+            ConversionError::UnalignedOffset { offset, alignment } => {
+                f.write_str("The buffer offset ")?;
+                Display::fmt(offset, f)?;
+                f.write_str(" is not a multiple of ")?;
+                Display::fmt(alignment, f)
+            }
+            ConversionError::InvalidExposure(exposure) => {
+                f.write_str("The exposure ")?;
+                Display::fmt(exposure, f)?;
+                f.write_str(" is not a finite number greater than zero")
+            }
+        }
+    }
+}
+
+impl Debug for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for ConversionError {}
+
+/// The pipelines of the conversion shaders, created on first use.
+pub(crate) struct Converter {
+    texture_to_buffer: wgpu::ComputePipeline,
+    /// Indexed like [`DESTINATION_FORMATS`].
+    buffer_to_texture: Vec<wgpu::ComputePipeline>,
+}
+
+impl Converter {
+    fn new(device: &wgpu::Device) -> Self {
+        let pipeline = |format: TextureFormat, entry_point| {
+            let format_name = match format {
+                TextureFormat::Rgba8Unorm => "rgba8unorm",
+                TextureFormat::Rgba16Float => "rgba16float",
+                TextureFormat::Rgba32Float => "rgba32float",
+                _ => unreachable!(),
+            };
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("oidn-wgpu-interop conversion"),
+                source: wgpu::ShaderSource::Wgsl(SHADER.replace("FORMAT", format_name).into()),
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        Self {
+            // the destination is unused, any storage format will do
+            texture_to_buffer: pipeline(TextureFormat::Rgba32Float, "texture_to_buffer"),
+            buffer_to_texture: DESTINATION_FORMATS
+                .into_iter()
+                .map(|format| pipeline(format, "buffer_to_texture"))
+                .collect(),
+        }
+    }
+}
+
+/// The part of a texture and of a buffer image a conversion covers.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Region {
+    pub(crate) size: [u32; 2],
+    pub(crate) texture_origin: [u32; 2],
+    /// Only used when writing textures, the whole buffer image is written otherwise.
+    pub(crate) buffer_origin: [u32; 2],
+    /// The width of the buffer image in pixels.
+    pub(crate) buffer_width: u32,
+}
+
+impl Region {
+    pub(crate) fn whole(texture: &wgpu::Texture) -> Self {
+        Self {
+            size: [texture.width(), texture.height()],
+            texture_origin: [0, 0],
+            buffer_origin: [0, 0],
+            buffer_width: texture.width(),
+        }
+    }
+
+    /// The size of the buffer image up to the last row of the region.
+    fn buffer_size(&self, format: BufferFormat) -> BufferAddress {
+        format.buffer_size(self.buffer_width, self.buffer_origin[1] + self.size[1])
+    }
+}
+
+impl Device {
+    /// Records a conversion of `texture` into the OIDN image in `buffer`.
+    ///
+    /// Only the first layer and mip level is converted. The texture must be 2D, have
+    /// [`TextureUsages::TEXTURE_BINDING`] and one of the formats `Rgba8Unorm`, `Rgba8UnormSrgb`,
+    /// `Rgba16Float` or `Rgba32Float`. The buffer must have [`BufferUsages::STORAGE`] and hold at
+    /// least [`BufferFormat::buffer_size`] bytes.
+    pub fn encode_texture_to_buffer(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        buffer: wgpu::BufferSlice<'_>,
+        options: &ConversionOptions,
+    ) -> Result<(), ConversionError> {
+        self.encode_texture_region_to_buffer(
+            encoder,
+            texture,
+            Region::whole(texture),
+            buffer,
+            options,
+        )
+    }
+
+    /// Like [`Device::encode_texture_to_buffer`], but converts the texels of `region` into a
+    /// buffer image of the region's size.
+    pub(crate) fn encode_texture_region_to_buffer(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        region: Region,
+        buffer: wgpu::BufferSlice<'_>,
+        options: &ConversionOptions,
+    ) -> Result<(), ConversionError> {
+        match texture.format() {
+            TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb
+            | TextureFormat::Rgba16Float
+            | TextureFormat::Rgba32Float => {}
+            format => return Err(ConversionError::UnsupportedFormat(format)),
+        }
+        let region = Region {
+            buffer_origin: [0, 0],
+            buffer_width: region.size[0],
+            ..region
+        };
+        self.check_usages(
+            texture,
+            TextureUsages::TEXTURE_BINDING,
+            buffer,
+            region,
+            options,
+        )?;
+        let converter = self.converter();
+        let view = first_layer_view(texture);
+        let srgb = options.srgb && !texture.format().is_srgb();
+        let pixels = region.size[0] * region.size[1];
+        let invocations = match options.buffer_format {
+            BufferFormat::Float3 => pixels,
+            BufferFormat::Half3 => pixels.div_ceil(2),
+        };
+        self.encode_conversion(
+            encoder,
+            &converter.texture_to_buffer,
+            region,
+            invocations,
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            buffer,
+            options.buffer_format,
+            options.exposure,
+            srgb,
+        );
+        Ok(())
+    }
+
+    /// Records a conversion of the OIDN image in `buffer` into `texture`, with an alpha of one.
+    ///
+    /// Only the first layer and mip level is written. The texture must be 2D, have
+    /// [`TextureUsages::STORAGE_BINDING`] and one of the formats `Rgba8Unorm`, `Rgba16Float` or
+    /// `Rgba32Float`. The buffer must have [`BufferUsages::STORAGE`] and hold at least
+    /// [`BufferFormat::buffer_size`] bytes.
+    pub fn encode_buffer_to_texture(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        buffer: wgpu::BufferSlice<'_>,
+        texture: &wgpu::Texture,
+        options: &ConversionOptions,
+    ) -> Result<(), ConversionError> {
+        self.encode_buffer_region_to_texture(
+            encoder,
+            buffer,
+            Region::whole(texture),
+            texture,
+            options,
+        )
+    }
+
+    /// Like [`Device::encode_buffer_to_texture`], but only writes the texels of `region`.
+    pub(crate) fn encode_buffer_region_to_texture(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        buffer: wgpu::BufferSlice<'_>,
+        region: Region,
+        texture: &wgpu::Texture,
+        options: &ConversionOptions,
+    ) -> Result<(), ConversionError> {
+        let Some(index) = DESTINATION_FORMATS
+            .iter()
+            .position(|format| *format == texture.format())
+        else {
+            return Err(ConversionError::UnsupportedFormat(texture.format()));
+        };
+        self.check_usages(
+            texture,
+            TextureUsages::STORAGE_BINDING,
+            buffer,
+            region,
+            options,
+        )?;
+        let converter = self.converter();
+        let view = first_layer_view(texture);
+        self.encode_conversion(
+            encoder,
+            &converter.buffer_to_texture[index],
+            region,
+            region.size[0] * region.size[1],
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            buffer,
+            options.buffer_format,
+            options.exposure.recip(),
+            options.srgb,
+        );
+        Ok(())
+    }
+
+    fn check_usages(
+        &self,
+        texture: &wgpu::Texture,
+        texture_usage: TextureUsages,
+        buffer: wgpu::BufferSlice<'_>,
+        region: Region,
+        options: &ConversionOptions,
+    ) -> Result<(), ConversionError> {
+        options.check()?;
+        debug_assert!(
+            region.texture_origin[0] + region.size[0] <= texture.width()
+                && region.texture_origin[1] + region.size[1] <= texture.height()
+        );
+        if texture.dimension() != wgpu::TextureDimension::D2 || texture.sample_count() != 1 {
+            return Err(ConversionError::UnsupportedTexture);
+        }
+        if !texture.usage().contains(texture_usage) {
+            return Err(ConversionError::MissingTextureUsage(
+                texture_usage - texture.usage(),
+            ));
+        }
+        if !buffer.buffer().usage().contains(BufferUsages::STORAGE) {
+            return Err(ConversionError::MissingBufferUsage(BufferUsages::STORAGE));
+        }
+        // storage bindings must start at an aligned offset
+        let alignment = self
+            .wgpu_device
+            .limits()
+            .min_storage_buffer_offset_alignment;
+        if buffer.offset() % BufferAddress::from(alignment) != 0 {
+            return Err(ConversionError::UnalignedOffset {
+                offset: buffer.offset(),
+                alignment,
+            });
+        }
+        let required = region.buffer_size(options.buffer_format);
+        let actual = buffer.size().get();
+        if actual < required {
+            return Err(ConversionError::BufferTooSmall { required, actual });
+        }
+        Ok(())
+    }
+
+    fn converter(&self) -> &Converter {
+        self.converter
+            .get_or_init(|| Converter::new(&self.wgpu_device))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn encode_conversion(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::ComputePipeline,
+        region: Region,
+        invocations: u32,
+        texture_entry: wgpu::BindGroupEntry<'_>,
+        buffer: wgpu::BufferSlice<'_>,
+        buffer_format: BufferFormat,
+        scale: f32,
+        srgb: bool,
+    ) {
+        let mut flags = 0;
+        if buffer_format == BufferFormat::Half3 {
+            flags |= HALF3;
+        }
+        if srgb {
+            flags |= SRGB;
+        }
+        // padded to the 48 bytes the uniform takes in the shader
+        let params = [
+            region.size[0],
+            region.size[1],
+            scale.to_bits(),
+            flags,
+            region.texture_origin[0],
+            region.texture_origin[1],
+            region.buffer_origin[0],
+            region.buffer_origin[1],
+            region.buffer_width,
+            0,
+            0,
+            0,
+        ];
+        let params: Vec<u8> = params.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        let params = self
+            .wgpu_device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("oidn-wgpu-interop conversion parameters"),
+                contents: &params,
+                usage: BufferUsages::UNIFORM,
+            });
+        let bind_group = self
+            .wgpu_device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: params.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: buffer.into(),
+                    },
+                    texture_entry,
+                ],
+            });
+
+        // spread the workgroups over two dimensions, large images exceed the limit of one
+        let workgroups = invocations.div_ceil(WORKGROUP_SIZE);
+        let max_workgroups = self
+            .wgpu_device
+            .limits()
+            .max_compute_workgroups_per_dimension;
+        let x = workgroups.min(max_workgroups);
+        let y = workgroups.div_ceil(x);
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("oidn-wgpu-interop conversion"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(x, y, 1);
+    }
+}
+
+/// Views the first layer and mip level, the part of a texture that is converted.
+fn first_layer_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        mip_level_count: Some(1),
+        array_layer_count: Some(1),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_shader_validates() {
+    use wgpu::naga;
+    for format in ["rgba8unorm", "rgba16float", "rgba32float"] {
+        let module = naga::front::wgsl::parse_str(&SHADER.replace("FORMAT", format)).unwrap();
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            // what wgpu enables on every adapter that isn't downlevel
+            naga::valid::Capabilities::SHADER_FLOAT16_IN_FLOAT32,
+        )
+        .validate(&module)
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_exposure_is_checked() {
+    let options = ConversionOptions::default();
+    assert!(options.check().is_ok());
+    for exposure in [0.0, -1.0, f32::INFINITY, f32::NAN] {
+        assert!(matches!(
+            ConversionOptions {
+                exposure,
+                ..options
+            }
+            .check(),
+            Err(ConversionError::InvalidExposure(_))
+        ));
+    }
+}
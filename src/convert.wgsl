@@ -0,0 +1,123 @@
+// Converts between RGBA textures and the packed float3 / half3 images OIDN reads.
+//
+// `FORMAT` is replaced with the storage format of the destination texture before compiling.
+
+struct Params {
+    // the size of the converted region
+    size: vec2<u32>,
+    // multiplies the linear colors, the exposure or its inverse when writing textures
+    scale: f32,
+    flags: u32,
+    // where the region starts in the texture
+    texture_origin: vec2<u32>,
+    // where the region starts in the buffer image, which is `buffer_width` pixels wide
+    buffer_origin: vec2<u32>,
+    buffer_width: u32,
+}
+
+// the buffer holds half3 instead of float3 pixels
+const HALF3: u32 = 1u;
+// the texture holds sRGB encoded colors
+const SRGB: u32 = 2u;
+
+const WORKGROUP_SIZE: u32 = 64u;
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read_write> pixels: array<u32>;
+@group(0) @binding(2) var source: texture_2d<f32>;
+@group(0) @binding(3) var destination: texture_storage_2d<FORMAT, write>;
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    return select(pow((color + 0.055) / 1.055, vec3(2.4)), color / 12.92, color <= vec3(0.04045));
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let c = max(color, vec3(0.0));
+    return select(1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3(0.0031308));
+}
+
+fn invocation(id: vec3<u32>, groups: vec3<u32>) -> u32 {
+    return id.y * groups.x * WORKGROUP_SIZE + id.x;
+}
+
+// The position of a pixel of the region, relative to its origin.
+fn coords(pixel: u32) -> vec2<u32> {
+    return vec2(pixel % params.size.x, pixel / params.size.x);
+}
+
+fn load_texel(pixel: u32) -> vec3<f32> {
+    var color = textureLoad(source, params.texture_origin + coords(pixel), 0).rgb;
+    if (params.flags & SRGB) != 0u {
+        color = srgb_to_linear(color);
+    }
+    return color * params.scale;
+}
+
+fn store_texel(pixel: u32, color: vec3<f32>) {
+    var c = color * params.scale;
+    if (params.flags & SRGB) != 0u {
+        c = linear_to_srgb(c);
+    }
+    textureStore(destination, params.texture_origin + coords(pixel), vec4(c, 1.0));
+}
+
+fn load_half(index: u32) -> f32 {
+    let halves = unpack2x16float(pixels[index / 2u]);
+    return select(halves.x, halves.y, index % 2u == 1u);
+}
+
+// Fills the whole buffer image with the region, `buffer_origin` and `buffer_width` are ignored.
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn texture_to_buffer(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let count = params.size.x * params.size.y;
+    // half3 invocations handle two pixels, so that no two invocations write to the same word
+    let index = invocation(id, groups);
+    let first = select(index, index * 2u, (params.flags & HALF3) != 0u);
+    if first >= count {
+        return;
+    }
+    let a = load_texel(first);
+    if (params.flags & HALF3) == 0u {
+        pixels[first * 3u] = bitcast<u32>(a.r);
+        pixels[first * 3u + 1u] = bitcast<u32>(a.g);
+        pixels[first * 3u + 2u] = bitcast<u32>(a.b);
+        return;
+    }
+    let word = first / 2u * 3u;
+    let has_second = first + 1u < count;
+    var b = vec3(0.0);
+    if has_second {
+        b = load_texel(first + 1u);
+    }
+    pixels[word] = pack2x16float(a.rg);
+    // with an odd pixel count the upper half of this word is padding
+    pixels[word + 1u] = pack2x16float(vec2(a.b, b.r));
+    if has_second {
+        pixels[word + 2u] = pack2x16float(b.gb);
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn buffer_to_texture(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) groups: vec3<u32>,
+) {
+    let pixel = invocation(id, groups);
+    if pixel >= params.size.x * params.size.y {
+        return;
+    }
+    let position = params.buffer_origin + coords(pixel);
+    let first = (position.y * params.buffer_width + position.x) * 3u;
+    if (params.flags & HALF3) == 0u {
+        store_texel(pixel, vec3(
+            bitcast<f32>(pixels[first]),
+            bitcast<f32>(pixels[first + 1u]),
+            bitcast<f32>(pixels[first + 2u]),
+        ));
+        return;
+    }
+    store_texel(pixel, vec3(load_half(first), load_half(first + 1u), load_half(first + 2u)));
+}
@@ -0,0 +1,1238 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt::{self, Debug, Display};
+
+use wgpu::BufferUsages;
+
+use crate::{
+    AccessError, BufferFormat, ConversionError, ConversionOptions, Device, FilterExecution,
+    MemoryLocation, SharedBuffer, SharedBufferCreateError, SharedBufferDescriptor, SyncError,
+    convert::Region, execute::ExecutionWaiter,
+};
+
+/// An OIDN filter used through its raw handle, the `oidn` crate's filters can only bind whole
+/// buffers of their own.
+pub(crate) struct RawFilter {
+    raw: oidn::sys::OIDNFilter,
+}
+
+// # SAFETY: OIDN filters may be used from any thread but not from several at once, which this not
+// being `Sync` ensures.
+unsafe impl Send for RawFilter {}
+
+impl RawFilter {
+    pub(crate) fn new(
+        device: &oidn::Device,
+        filter_type: &CStr,
+    ) -> Result<Self, (oidn::Error, String)> {
+        let raw = unsafe { oidn::sys::oidnNewFilter(device.raw(), filter_type.as_ptr()) };
+        if raw.is_null() {
+            return Err(device.get_error().err().unwrap_or_else(|| {
+                (
+                    oidn::Error::Unknown,
+                    "the filter could not be created".to_owned(),
+                )
+            }));
+        }
+        Ok(Self { raw })
+    }
+
+    /// Another handle to the same filter, which keeps it alive on its own.
+    pub(crate) fn retain(&self) -> Self {
+        unsafe { oidn::sys::oidnRetainFilter(self.raw) };
+        Self { raw: self.raw }
+    }
+
+    pub(crate) fn set_bool(&self, name: &CStr, value: bool) {
+        unsafe { oidn::sys::oidnSetFilterBool(self.raw, name.as_ptr(), value) }
+    }
+
+    pub(crate) fn set_int(&self, name: &CStr, value: i32) {
+        unsafe { oidn::sys::oidnSetFilterInt(self.raw, name.as_ptr(), value) }
+    }
+
+    pub(crate) fn set_float(&self, name: &CStr, value: f32) {
+        unsafe { oidn::sys::oidnSetFilterFloat(self.raw, name.as_ptr(), value) }
+    }
+
+    /// Binds a tightly packed image of `size` pixels at the start of `buffer`.
+    pub(crate) fn set_image(
+        &self,
+        name: &CStr,
+        buffer: &oidn::Buffer,
+        format: BufferFormat,
+        size: [u32; 2],
+    ) {
+        unsafe {
+            oidn::sys::oidnSetFilterImage(
+                self.raw,
+                name.as_ptr(),
+                buffer.raw(),
+                format.oidn_format(),
+                size[0] as usize,
+                size[1] as usize,
+                0,
+                0,
+                0,
+            )
+        }
+    }
+
+    pub(crate) fn get_int(&self, name: &CStr) -> i32 {
+        unsafe { oidn::sys::oidnGetFilterInt(self.raw, name.as_ptr()) }
+    }
+
+    pub(crate) fn commit(&self) {
+        unsafe { oidn::sys::oidnCommitFilter(self.raw) }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn raw(&self) -> oidn::sys::OIDNFilter {
+        self.raw
+    }
+
+    pub(crate) fn execute(&self) {
+        unsafe { oidn::sys::oidnExecuteFilter(self.raw) }
+    }
+}
+
+impl Drop for RawFilter {
+    fn drop(&mut self) {
+        unsafe { oidn::sys::oidnReleaseFilter(self.raw) }
+    }
+}
+
+/// The parameters of OIDN's `RT` filter, see its documentation for details.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct DenoiserSettings {
+    /// The color image is high dynamic range.
+    pub hdr: bool,
+    /// The color image is sRGB encoded, only allowed without `hdr`.
+    pub srgb: bool,
+    /// How the albedo and normal images are used.
+    pub aux_mode: AuxMode,
+    pub quality: oidn::Quality,
+    /// Scales the color image to about one for HDR images, `None` lets OIDN pick it.
+    pub input_scale: Option<f32>,
+}
+
+/// Whether the albedo and normal images of a [`Denoiser`] are noisy, and what to do about it.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum AuxMode {
+    /// The images are noisy and are denoised on their own before the color, in place. This
+    /// gives the best quality.
+    #[default]
+    Prefilter,
+    /// The images are noisy and are used as they are, which is faster.
+    Noisy,
+    /// The images are noise free, like those of a rasterized G-buffer.
+    Clean,
+}
+
+/// Describes a [`Denoiser`].
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct DenoiserDescriptor {
+    pub width: u32,
+    pub height: u32,
+    /// The format of the shared buffers.
+    pub buffer_format: BufferFormat,
+    /// Whether an albedo image is denoised along with the color.
+    pub albedo: bool,
+    /// Whether a normal image is denoised along with the color, requires `albedo`.
+    pub normal: bool,
+    pub settings: DenoiserSettings,
+    /// Denoises the image in overlapping tiles of at most this many pixels along each side,
+    /// which the shared buffers and OIDN's memory are sized for, instead of all at once. `None`
+    /// disables tiling. A tiled denoiser only denoises through [`Denoiser::denoise`].
+    pub tile_size: Option<u32>,
+}
+
+impl DenoiserDescriptor {
+    fn shape(&self) -> ImageShape {
+        ImageShape {
+            format: self.buffer_format,
+            width: self.width,
+            height: self.height,
+            tile_size: self.tile_size,
+        }
+    }
+}
+
+/// The textures [`Denoiser::encode_inputs`] and [`Denoiser::denoise`] read, matching the images
+/// the denoiser was created with.
+#[derive(Clone, Copy, Debug)]
+pub struct DenoiseInputs<'a> {
+    pub color: &'a wgpu::Texture,
+    pub albedo: Option<&'a wgpu::Texture>,
+    pub normal: Option<&'a wgpu::Texture>,
+}
+
+pub enum DenoiseError {
+    /// Allocating the shared buffers failed.
+    Allocation(SharedBufferCreateError),
+    Conversion(ConversionError),
+    Sync(SyncError),
+    /// A shared buffer is not owned by the API the denoiser needs it for, as it was released
+    /// and not acquired.
+    Access(AccessError),
+    Oidn((oidn::Error, String)),
+    /// A texture has a different size than the denoiser.
+    SizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// The named image is needed but was not given.
+    MissingInput(&'static str),
+    /// The named image was given but the denoiser was created without it.
+    UnexpectedInput(&'static str),
+    /// The tile size leaves no room between the overlap the filter needs on both sides.
+    TileTooSmall {
+        tile_size: u32,
+        overlap: u32,
+    },
+    /// The denoiser is tiled, so its images only fit into the shared buffers one tile at a time
+    /// and it only denoises through [`Denoiser::denoise`].
+    Tiled,
+    /// The output texture of a tiled denoiser is one of the inputs, which the tiles next to the
+    /// ones already written still read.
+    OutputIsInput,
+}
+
+impl Display for DenoiseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DenoiseError::Allocation(err) => {
+                f.write_str("Allocating the denoiser's buffers failed: ")?;
+                Display::fmt(err, f)
+            }
+            DenoiseError::Conversion(err) => {
+                f.write_str("Converting an image failed: ")?;
+                Display::fmt(err, f)
+            }
+            DenoiseError::Sync(err) => Display::fmt(err, f),
+            DenoiseError::Access(err) => Display::fmt(err, f),
+            DenoiseError::Oidn((error, desc)) => {
+                f.write_str("OIDN filter failed with error ")?;
+                Debug::fmt(error, f)?;
+                f.write_str(": ")?;
+                f.write_str(desc)
+            }
+            DenoiseError::SizeMismatch { expected, actual } => {
+                f.write_str("Expected a ")?;
+                Display::fmt(&expected.0, f)?;
+                f.write_str("x")?;
+                Display::fmt(&expected.1, f)?;
+                f.write_str(" texture but got a ")?;
+                Display::fmt(&actual.0, f)?;
+                f.write_str("x")?;
+                Display::fmt(&actual.1, f)?;
+                f.write_str(" one")
+            }
+            DenoiseError::MissingInput(name) => {
+                f.write_str("The ")?;
+                f.write_str(name)?;
+                f.write_str(" image is missing")
+            }
+            DenoiseError::UnexpectedInput(name) => {
+                f.write_str("The denoiser was created without the ")?;
+                f.write_str(name)?;
+                f.write_str(" image")
+            }
+            DenoiseError::TileTooSmall { tile_size, overlap } => {
+                f.write_str("Tiles of ")?;
+                Display::fmt(tile_size, f)?;
+                f.write_str(" pixels are too small for an overlap of ")?;
+                Display::fmt(overlap, f)?;
+                f.write_str(" pixels")
+            }
+            DenoiseError::Tiled => {
+                f.write_str("A tiled denoiser can only denoise a whole image at once")
+            }
+            DenoiseError::OutputIsInput => {
+                f.write_str("The output of a tiled denoiser can't be one of its inputs")
+            }
+        }
+    }
+}
+
+impl Debug for DenoiseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for DenoiseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DenoiseError::Allocation(err) => Some(err),
+            DenoiseError::Conversion(err) => Some(err),
+            DenoiseError::Sync(err) => Some(err),
+            DenoiseError::Access(err) => Some(err),
+            DenoiseError::Oidn(_)
+            | DenoiseError::SizeMismatch { .. }
+            | DenoiseError::MissingInput(_)
+            | DenoiseError::UnexpectedInput(_)
+            | DenoiseError::TileTooSmall { .. }
+            | DenoiseError::Tiled
+            | DenoiseError::OutputIsInput => None,
+        }
+    }
+}
+
+impl From<SharedBufferCreateError> for DenoiseError {
+    fn from(err: SharedBufferCreateError) -> Self {
+        DenoiseError::Allocation(err)
+    }
+}
+
+impl From<ConversionError> for DenoiseError {
+    fn from(err: ConversionError) -> Self {
+        DenoiseError::Conversion(err)
+    }
+}
+
+impl From<SyncError> for DenoiseError {
+    fn from(err: SyncError) -> Self {
+        DenoiseError::Sync(err)
+    }
+}
+
+impl From<AccessError> for DenoiseError {
+    fn from(err: AccessError) -> Self {
+        DenoiseError::Access(err)
+    }
+}
+
+/// Denoises textures with OIDN's `RT` filter through shared buffers it owns.
+///
+/// With tiling the shared buffers hold a single tile, see [`DenoiserDescriptor::tile_size`].
+pub struct Denoiser<'a> {
+    device: &'a Device,
+    filter: RawFilter,
+    /// The filters denoising the albedo and normal images, run before `filter` if the aux mode
+    /// is [`AuxMode::Prefilter`].
+    prefilters: Vec<(&'static CStr, RawFilter)>,
+    desc: DenoiserDescriptor,
+    color: SharedBuffer,
+    albedo: Option<SharedBuffer>,
+    normal: Option<SharedBuffer>,
+    output: SharedBuffer,
+    /// The tiles the filters run on, a single one covering the image without tiling.
+    tiles: Vec<Tile>,
+    /// The work started by [`Denoiser::execute_async`], which may still run.
+    pending: Cell<Option<ExecutionWaiter>>,
+}
+
+impl Device {
+    /// Creates a denoiser and the shared buffers of its images.
+    pub fn create_denoiser(&self, desc: &DenoiserDescriptor) -> Result<Denoiser<'_>, DenoiseError> {
+        if desc.normal && !desc.albedo {
+            return Err(DenoiseError::MissingInput("albedo"));
+        }
+        let shape = desc.shape();
+        let allocate = |label| allocate_image(self, label, shape);
+        let color = allocate("denoiser color")?;
+        let albedo = desc
+            .albedo
+            .then(|| allocate("denoiser albedo"))
+            .transpose()?;
+        let normal = desc
+            .normal
+            .then(|| allocate("denoiser normal"))
+            .transpose()?;
+        let output = allocate("denoiser output")?;
+
+        let filter = RawFilter::new(&self.oidn_device, c"RT").map_err(DenoiseError::Oidn)?;
+        let mut denoiser = Denoiser {
+            device: self,
+            filter,
+            prefilters: Vec::new(),
+            desc: *desc,
+            color,
+            albedo,
+            normal,
+            output,
+            tiles: Vec::new(),
+            pending: Cell::new(None),
+        };
+        denoiser.apply_settings()?;
+        Ok(denoiser)
+    }
+}
+
+impl Denoiser<'_> {
+    fn apply_settings(&mut self) -> Result<(), DenoiseError> {
+        self.finish_pending();
+        let settings = self.desc.settings;
+        let quality = settings.quality.as_raw_oidn_quality() as i32;
+        self.filter.set_bool(c"hdr", settings.hdr);
+        self.filter.set_bool(c"srgb", settings.srgb);
+        self.filter
+            .set_bool(c"cleanAux", settings.aux_mode != AuxMode::Noisy);
+        self.filter.set_int(c"quality", quality);
+        self.filter
+            .set_float(c"inputScale", settings.input_scale.unwrap_or(f32::NAN));
+
+        self.prefilters.clear();
+        if settings.aux_mode == AuxMode::Prefilter {
+            for (name, buffer) in [(c"albedo", &self.albedo), (c"normal", &self.normal)] {
+                if buffer.is_none() {
+                    continue;
+                }
+                let prefilter =
+                    RawFilter::new(&self.device.oidn_device, c"RT").map_err(DenoiseError::Oidn)?;
+                prefilter.set_int(c"quality", quality);
+                self.prefilters.push((name, prefilter));
+            }
+        }
+        // binding the images doesn't touch their contents, so wgpu may still use the buffers
+        let stages = self.stages(|buffer| Ok(&buffer.oidn_buffer))?;
+        self.tiles = commit_stages(&self.device.oidn_device, &stages, self.desc.shape())?;
+        Ok(())
+    }
+
+    pub fn settings(&self) -> DenoiserSettings {
+        self.desc.settings
+    }
+
+    pub fn set_settings(&mut self, settings: DenoiserSettings) -> Result<(), DenoiseError> {
+        self.desc.settings = settings;
+        self.apply_settings()
+    }
+
+    pub fn color_buffer(&self) -> &SharedBuffer {
+        &self.color
+    }
+    pub fn albedo_buffer(&self) -> Option<&SharedBuffer> {
+        self.albedo.as_ref()
+    }
+    pub fn normal_buffer(&self) -> Option<&SharedBuffer> {
+        self.normal.as_ref()
+    }
+    pub fn output_buffer(&self) -> &SharedBuffer {
+        &self.output
+    }
+
+    /// Denoises `inputs` into `output`, tile by tile if the denoiser is tiled.
+    ///
+    /// The conversions of every tile are submitted to the device's queue, and this blocks until
+    /// wgpu and OIDN are done with a tile before the next one reuses the shared buffers. The
+    /// conversion of the last tile's output is submitted but not waited for. The textures must
+    /// meet the requirements of [`Device::encode_texture_to_buffer`] and
+    /// [`Device::encode_buffer_to_texture`], and with tiling `output` can't be one of the inputs.
+    pub fn denoise(
+        &self,
+        inputs: &DenoiseInputs<'_>,
+        output: &wgpu::Texture,
+    ) -> Result<(), DenoiseError> {
+        let filter_inputs = self.filter_inputs(inputs)?;
+        self.finish_pending();
+        let shape = self.desc.shape();
+        check_sizes(&filter_inputs, Some(output), shape)?;
+        // `denoise_tiles` hands the buffers to OIDN before every run
+        let job = FilterJob {
+            stages: self.stages(|buffer| Ok(&buffer.oidn_buffer))?,
+            shape,
+        };
+        denoise_tiles(
+            self.device,
+            &self.tiles,
+            &job,
+            &filter_inputs,
+            (output, &self.output),
+        )
+    }
+
+    /// Records the conversion of `inputs` into the shared buffers.
+    ///
+    /// Once `encoder` is submitted, [`Denoiser::execute`] denoises them and
+    /// [`Denoiser::encode_output`] records the conversion of the result. The textures must meet
+    /// the requirements of [`Device::encode_texture_to_buffer`].
+    ///
+    /// Fails with [`DenoiseError::Tiled`] if the denoiser is tiled.
+    pub fn encode_inputs(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        inputs: &DenoiseInputs<'_>,
+    ) -> Result<(), DenoiseError> {
+        self.desc.shape().check_untiled()?;
+        let filter_inputs = self.filter_inputs(inputs)?;
+        self.finish_pending();
+        let shape = self.desc.shape();
+        check_sizes(&filter_inputs, None, shape)?;
+        // no filters are pending, so OIDN is done with the inputs
+        for (_, buffer) in &filter_inputs {
+            buffer.owner.acquire_wgpu()?;
+        }
+        encode_inputs(
+            self.device,
+            encoder,
+            &filter_inputs,
+            &shape.conversion_options(),
+        )
+    }
+
+    /// Denoises the inputs once wgpu finished `submission`, the submission of the encoder
+    /// [`Denoiser::encode_inputs`] recorded into.
+    ///
+    /// Blocks until wgpu and then OIDN are done. The conversion of the previous result by
+    /// [`Denoiser::encode_output`] must have been submitted before `submission`.
+    pub fn execute(&self, submission: wgpu::SubmissionIndex) -> Result<(), DenoiseError> {
+        self.desc.shape().check_untiled()?;
+        self.finish_pending();
+        wait_for_inputs(self.device, &self.input_buffers(), &self.output, submission)?;
+        self.job()?.run(&self.device.oidn_device, None)
+    }
+
+    /// Denoises the inputs once wgpu finished `submission`, like [`Denoiser::execute`], without
+    /// blocking.
+    ///
+    /// Waiting for wgpu and running the filters happen on the device's worker thread, which runs
+    /// the work of all its denoisers in order, and the returned future resolves when they are
+    /// done. Meanwhile wgpu work that doesn't use the denoiser's
+    /// buffers can be recorded and submitted. Then [`Denoiser::encode_output`] records the
+    /// conversion of the result.
+    ///
+    /// Other uses of the denoiser wait for the worker first.
+    pub fn execute_async(
+        &self,
+        submission: wgpu::SubmissionIndex,
+    ) -> Result<FilterExecution, DenoiseError> {
+        self.desc.shape().check_untiled()?;
+        self.finish_pending();
+        let inputs = self.input_buffers();
+        // the worker finishes the waits before OIDN uses the buffers, `finish_pending` acquires
+        // them afterwards
+        for buffer in inputs.iter().copied().chain([&self.output]) {
+            buffer.owner.release_oidn()?;
+        }
+        // the filters only run after the waits, the released buffers can't be claimed yet
+        let job = FilterJob {
+            stages: self.stages(|buffer| Ok(&buffer.oidn_buffer))?,
+            shape: self.desc.shape(),
+        };
+        let waits: Vec<_> = inputs
+            .into_iter()
+            .map(|buffer| {
+                self.device
+                    .start_wait_for_wgpu(buffer, Some(submission.clone()))
+            })
+            .collect();
+        let wgpu_device = self.device.wgpu_device.clone();
+        // the worker holds a reference of its own, the device may be dropped before it's done
+        let oidn_device = unsafe {
+            let raw = self.device.oidn_device.raw();
+            oidn::sys::oidnRetainDevice(raw);
+            oidn::Device::from_raw(raw)
+        };
+        let (execution, waiter) = self.device.spawn_execution(move || {
+            for wait in waits {
+                wait.finish(&wgpu_device)?;
+            }
+            job.run(&oidn_device, None)
+        });
+        self.pending.set(Some(waiter));
+        Ok(execution)
+    }
+
+    /// Records the conversion of the last result into `output`, waiting for the worker if the
+    /// work started by [`Denoiser::execute_async`] hasn't finished.
+    ///
+    /// `output` must meet the requirements of [`Device::encode_buffer_to_texture`], and may be
+    /// one of the inputs.
+    pub fn encode_output(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::Texture,
+    ) -> Result<(), DenoiseError> {
+        let shape = self.desc.shape();
+        shape.check_untiled()?;
+        check_sizes(&[], Some(output), shape)?;
+        self.finish_pending();
+        convert_output(
+            self.device,
+            encoder,
+            (output, &self.output),
+            &shape.conversion_options(),
+        )
+    }
+
+    fn filter_inputs<'t>(
+        &'t self,
+        inputs: &DenoiseInputs<'t>,
+    ) -> Result<Vec<(&'t wgpu::Texture, &'t SharedBuffer)>, DenoiseError> {
+        let images = [
+            ("color", Some(inputs.color), Some(&self.color)),
+            ("albedo", inputs.albedo, self.albedo.as_ref()),
+            ("normal", inputs.normal, self.normal.as_ref()),
+        ];
+        let mut filter_inputs = Vec::with_capacity(images.len());
+        for (name, texture, buffer) in images {
+            match (texture, buffer) {
+                (Some(texture), Some(buffer)) => filter_inputs.push((texture, buffer)),
+                (None, Some(_)) => return Err(DenoiseError::MissingInput(name)),
+                (Some(_), None) => return Err(DenoiseError::UnexpectedInput(name)),
+                (None, None) => {}
+            }
+        }
+        Ok(filter_inputs)
+    }
+
+    /// The buffers of the input images the denoiser was created with.
+    fn input_buffers(&self) -> Vec<&SharedBuffer> {
+        [
+            Some(&self.color),
+            self.albedo.as_ref(),
+            self.normal.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// The prefilters and then the main filter, with the images they are bound to.
+    ///
+    /// The prefilters denoise the albedo and normal images in place. With tiling the buffers
+    /// only hold the current tile, so this doesn't change what the tiles next to it read.
+    fn stages<'s>(
+        &'s self,
+        oidn_buffer: impl Fn(&'s SharedBuffer) -> Result<&'s oidn::Buffer, AccessError>,
+    ) -> Result<Vec<Stage>, DenoiseError> {
+        let retain = |buffer| self.device.retain_oidn_buffer(buffer);
+        let aux = [
+            (c"albedo", self.albedo.as_ref()),
+            (c"normal", self.normal.as_ref()),
+        ];
+        let mut stages = Vec::with_capacity(self.prefilters.len() + 1);
+        let mut images = vec![(c"color", retain(oidn_buffer(&self.color)?))];
+        for (name, buffer) in aux {
+            let Some(buffer) = buffer else {
+                continue;
+            };
+            let image = oidn_buffer(buffer)?;
+            if let Some((_, prefilter)) = self.prefilters.iter().find(|(aux, _)| *aux == name) {
+                stages.push(Stage {
+                    filter: prefilter.retain(),
+                    images: vec![(name, retain(image)), (c"output", retain(image))],
+                });
+            }
+            images.push((name, retain(image)));
+        }
+        images.push((c"output", retain(oidn_buffer(&self.output)?)));
+        stages.push(Stage {
+            filter: self.filter.retain(),
+            images,
+        });
+        Ok(stages)
+    }
+
+    /// The stages to run, holding their own references to the filters and buffers.
+    fn job(&self) -> Result<FilterJob, DenoiseError> {
+        Ok(FilterJob {
+            stages: self.stages(SharedBuffer::checked_oidn_buffer)?,
+            shape: self.desc.shape(),
+        })
+    }
+
+    /// Waits for the work started by [`Denoiser::execute_async`], its errors are reported
+    /// through its future.
+    fn finish_pending(&self) {
+        if let Some(pending) = self.pending.take() {
+            pending.wait();
+            // released by `execute_async`, the worker waited for wgpu before the filters ran
+            for buffer in self.input_buffers().into_iter().chain([&self.output]) {
+                let _ = buffer.owner.acquire_oidn();
+            }
+        }
+    }
+}
+
+impl Drop for Denoiser<'_> {
+    fn drop(&mut self) {
+        // the filters may still use the buffers
+        self.finish_pending();
+    }
+}
+
+/// The parameters of OIDN's `RTLightmap` filter, whose input is always high dynamic range.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct LightmapSettings {
+    /// The lightmap holds normalized directional coefficients in `[-1, 1]` instead of
+    /// irradiance.
+    pub directional: bool,
+    pub quality: oidn::Quality,
+    /// Scales the irradiance to about one, `None` lets OIDN pick it. Ignored for directional
+    /// lightmaps.
+    pub input_scale: Option<f32>,
+}
+
+/// Describes a [`LightmapDenoiser`].
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct LightmapDenoiserDescriptor {
+    /// The size of the lightmap atlas.
+    pub width: u32,
+    pub height: u32,
+    /// The format of the shared buffers.
+    pub buffer_format: BufferFormat,
+    pub settings: LightmapSettings,
+    /// Denoises the atlas in tiles, like [`DenoiserDescriptor::tile_size`].
+    pub tile_size: Option<u32>,
+}
+
+impl LightmapDenoiserDescriptor {
+    fn shape(&self) -> ImageShape {
+        ImageShape {
+            format: self.buffer_format,
+            width: self.width,
+            height: self.height,
+            tile_size: self.tile_size,
+        }
+    }
+}
+
+/// Denoises lightmap atlases with OIDN's `RTLightmap` filter through shared buffers it owns.
+pub struct LightmapDenoiser<'a> {
+    device: &'a Device,
+    filter: RawFilter,
+    desc: LightmapDenoiserDescriptor,
+    color: SharedBuffer,
+    output: SharedBuffer,
+    /// The tiles the filter runs on, a single one covering the atlas without tiling.
+    tiles: Vec<Tile>,
+}
+
+impl Device {
+    /// Creates a lightmap denoiser and the shared buffers of its images.
+    pub fn create_lightmap_denoiser(
+        &self,
+        desc: &LightmapDenoiserDescriptor,
+    ) -> Result<LightmapDenoiser<'_>, DenoiseError> {
+        let shape = desc.shape();
+        let color = allocate_image(self, "lightmap denoiser color", shape)?;
+        let output = allocate_image(self, "lightmap denoiser output", shape)?;
+        let filter =
+            RawFilter::new(&self.oidn_device, c"RTLightmap").map_err(DenoiseError::Oidn)?;
+        let mut denoiser = LightmapDenoiser {
+            device: self,
+            filter,
+            desc: *desc,
+            color,
+            output,
+            tiles: Vec::new(),
+        };
+        denoiser.apply_settings()?;
+        Ok(denoiser)
+    }
+}
+
+impl LightmapDenoiser<'_> {
+    fn apply_settings(&mut self) -> Result<(), DenoiseError> {
+        let settings = &self.desc.settings;
+        self.filter.set_bool(c"directional", settings.directional);
+        self.filter
+            .set_int(c"quality", settings.quality.as_raw_oidn_quality() as i32);
+        self.filter
+            .set_float(c"inputScale", settings.input_scale.unwrap_or(f32::NAN));
+        // binding the images doesn't touch their contents, so wgpu may still use the buffers
+        let stages = self.stages(|buffer| Ok(&buffer.oidn_buffer))?;
+        self.tiles = commit_stages(&self.device.oidn_device, &stages, self.desc.shape())?;
+        Ok(())
+    }
+
+    pub fn settings(&self) -> LightmapSettings {
+        self.desc.settings
+    }
+
+    pub fn set_settings(&mut self, settings: LightmapSettings) -> Result<(), DenoiseError> {
+        self.desc.settings = settings;
+        self.apply_settings()
+    }
+
+    pub fn color_buffer(&self) -> &SharedBuffer {
+        &self.color
+    }
+    pub fn output_buffer(&self) -> &SharedBuffer {
+        &self.output
+    }
+
+    /// Denoises the lightmap atlas `input` into `output`, tile by tile if the denoiser is tiled,
+    /// like [`Denoiser::denoise`].
+    pub fn denoise(
+        &self,
+        input: &wgpu::Texture,
+        output: &wgpu::Texture,
+    ) -> Result<(), DenoiseError> {
+        let shape = self.desc.shape();
+        let inputs = [(input, &self.color)];
+        check_sizes(&inputs, Some(output), shape)?;
+        // `denoise_tiles` hands the buffers to OIDN before every run
+        let job = FilterJob {
+            stages: self.stages(|buffer| Ok(&buffer.oidn_buffer))?.into(),
+            shape,
+        };
+        denoise_tiles(
+            self.device,
+            &self.tiles,
+            &job,
+            &inputs,
+            (output, &self.output),
+        )
+    }
+
+    /// Records the conversion of the lightmap atlas `input` into the shared color buffer, like
+    /// [`Denoiser::encode_inputs`].
+    pub fn encode_input(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::Texture,
+    ) -> Result<(), DenoiseError> {
+        let shape = self.desc.shape();
+        shape.check_untiled()?;
+        let inputs = [(input, &self.color)];
+        check_sizes(&inputs, None, shape)?;
+        // the filter runs synchronously, so OIDN is done with the input
+        self.color.owner.acquire_wgpu()?;
+        encode_inputs(self.device, encoder, &inputs, &shape.conversion_options())
+    }
+
+    /// Denoises the atlas once wgpu finished `submission`, like [`Denoiser::execute`].
+    pub fn execute(&self, submission: wgpu::SubmissionIndex) -> Result<(), DenoiseError> {
+        self.desc.shape().check_untiled()?;
+        wait_for_inputs(self.device, &[&self.color], &self.output, submission)?;
+        let job = FilterJob {
+            stages: self.stages(SharedBuffer::checked_oidn_buffer)?.into(),
+            shape: self.desc.shape(),
+        };
+        job.run(&self.device.oidn_device, None)
+    }
+
+    /// Records the conversion of the denoised atlas into `output`, like
+    /// [`Denoiser::encode_output`].
+    pub fn encode_output(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::Texture,
+    ) -> Result<(), DenoiseError> {
+        let shape = self.desc.shape();
+        shape.check_untiled()?;
+        check_sizes(&[], Some(output), shape)?;
+        convert_output(
+            self.device,
+            encoder,
+            (output, &self.output),
+            &shape.conversion_options(),
+        )
+    }
+
+    /// The filter with the images it is bound to.
+    fn stages<'s>(
+        &'s self,
+        oidn_buffer: impl Fn(&'s SharedBuffer) -> Result<&'s oidn::Buffer, AccessError>,
+    ) -> Result<[Stage; 1], DenoiseError> {
+        let retain = |buffer| self.device.retain_oidn_buffer(buffer);
+        Ok([Stage {
+            filter: self.filter.retain(),
+            images: vec![
+                (c"color", retain(oidn_buffer(&self.color)?)),
+                (c"output", retain(oidn_buffer(&self.output)?)),
+            ],
+        }])
+    }
+}
+
+/// The format and size of the image a filter denoises, and how it is split into tiles.
+#[derive(Clone, Copy)]
+struct ImageShape {
+    format: BufferFormat,
+    width: u32,
+    height: u32,
+    tile_size: Option<u32>,
+}
+
+impl ImageShape {
+    fn conversion_options(&self) -> ConversionOptions {
+        ConversionOptions {
+            buffer_format: self.format,
+            ..Default::default()
+        }
+    }
+
+    /// The size of the images bound to the filters, the largest tile.
+    fn tile_extent(&self) -> (u32, u32) {
+        let tile_size = self.tile_size.unwrap_or(u32::MAX);
+        (self.width.min(tile_size), self.height.min(tile_size))
+    }
+
+    fn is_tiled(&self) -> bool {
+        self.tile_extent() != (self.width, self.height)
+    }
+
+    /// Fails for the functions that need the whole image in the shared buffers.
+    fn check_untiled(&self) -> Result<(), DenoiseError> {
+        if self.is_tiled() {
+            return Err(DenoiseError::Tiled);
+        }
+        Ok(())
+    }
+}
+
+/// A rectangle of an image, in pixels.
+#[derive(Clone, Copy, Debug)]
+struct Rect {
+    origin: [u32; 2],
+    size: [u32; 2],
+}
+
+/// A part of the image denoised on its own.
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    /// What the filters read and write, including the overlap with the tiles next to it.
+    input: Rect,
+    /// What is kept of the filter's output, the input without the overlap.
+    output: Rect,
+}
+
+impl Tile {
+    /// Converts the input of the tile into a buffer image of its size.
+    fn input_region(&self) -> Region {
+        Region {
+            size: self.input.size,
+            texture_origin: self.input.origin,
+            buffer_origin: [0, 0],
+            buffer_width: self.input.size[0],
+        }
+    }
+
+    /// Converts the output of the tile from the buffer image of the input's size.
+    fn output_region(&self) -> Region {
+        Region {
+            size: self.output.size,
+            texture_origin: self.output.origin,
+            buffer_origin: [
+                self.output.origin[0] - self.input.origin[0],
+                self.output.origin[1] - self.input.origin[1],
+            ],
+            buffer_width: self.input.size[0],
+        }
+    }
+}
+
+/// Splits the image into tiles whose outputs cover it exactly once.
+///
+/// The tiles overlap by `overlap` pixels on every side that is not on the border of the image,
+/// so their outputs match up, and start at multiples of `alignment`. The last tiles along each
+/// axis end at the border of the image, so they may be smaller than the others.
+fn tiles(shape: ImageShape, overlap: u32, alignment: u32) -> Result<Vec<Tile>, DenoiseError> {
+    let (tile_width, tile_height) = shape.tile_extent();
+    let alignment = alignment.max(1);
+    let overlap = overlap.next_multiple_of(alignment);
+    // the tile origin and size, and the start and end of the output along one axis
+    let spans = |size: u32, tile_size: u32| {
+        if tile_size >= size {
+            return Ok(vec![(0, size, 0, size)]);
+        }
+        let step = tile_size.saturating_sub(2 * overlap) / alignment * alignment;
+        if step == 0 {
+            return Err(DenoiseError::TileTooSmall { tile_size, overlap });
+        }
+        Ok((0..size.div_ceil(step))
+            .map(|i| {
+                let start = i * step;
+                let origin = start.saturating_sub(overlap);
+                (
+                    origin,
+                    tile_size.min(size - origin),
+                    start,
+                    (start + step).min(size),
+                )
+            })
+            .collect())
+    };
+    let columns = spans(shape.width, tile_width)?;
+    let rows = spans(shape.height, tile_height)?;
+
+    let mut tiles = Vec::with_capacity(columns.len() * rows.len());
+    for &(y, height, top, bottom) in &rows {
+        for &(x, width, left, right) in &columns {
+            tiles.push(Tile {
+                input: Rect {
+                    origin: [x, y],
+                    size: [width, height],
+                },
+                output: Rect {
+                    origin: [left, top],
+                    size: [right - left, bottom - top],
+                },
+            });
+        }
+    }
+    Ok(tiles)
+}
+
+/// A filter and the buffers its images are bound to, holding references of its own so that it
+/// can run on another thread.
+struct Stage {
+    filter: RawFilter,
+    images: Vec<(&'static CStr, oidn::Buffer)>,
+}
+
+impl Stage {
+    /// Binds images of `size` pixels, the size of the current tile, and commits the filter.
+    fn bind(&self, shape: ImageShape, size: [u32; 2]) {
+        for (name, buffer) in &self.images {
+            self.filter.set_image(name, buffer, shape.format, size);
+        }
+        self.filter.commit();
+    }
+}
+
+/// Binds the first tile to every stage and commits the filters, then splits the image into the
+/// tiles their overlap and alignment call for.
+fn commit_stages(
+    device: &oidn::Device,
+    stages: &[Stage],
+    shape: ImageShape,
+) -> Result<Vec<Tile>, DenoiseError> {
+    let (width, height) = shape.tile_extent();
+    for stage in stages {
+        stage.bind(shape, [width, height]);
+    }
+    device.get_error().map_err(DenoiseError::Oidn)?;
+
+    // every filter has to see its overlap, the prefilters may need less than the main filter
+    let overlap = stages
+        .iter()
+        .map(|stage| stage.filter.get_int(c"tileOverlap"))
+        .max()
+        .unwrap_or(0);
+    let alignment = stages
+        .iter()
+        .map(|stage| stage.filter.get_int(c"tileAlignment"))
+        .max()
+        .unwrap_or(1);
+    tiles(shape, overlap.max(0) as u32, alignment.max(1) as u32)
+}
+
+/// The filters of a denoiser, in the order they run.
+struct FilterJob {
+    stages: Vec<Stage>,
+    shape: ImageShape,
+}
+
+impl FilterJob {
+    /// Runs the stages in order, after binding images of `size` pixels if the current tile
+    /// isn't the one bound when the filters were committed.
+    fn run(&self, device: &oidn::Device, size: Option<[u32; 2]>) -> Result<(), DenoiseError> {
+        if let Some(size) = size {
+            for stage in &self.stages {
+                stage.bind(self.shape, size);
+            }
+        }
+        for stage in &self.stages {
+            stage.filter.execute();
+            device.get_error().map_err(DenoiseError::Oidn)?;
+        }
+        Ok(())
+    }
+}
+
+/// Allocates a shared buffer holding one tile of the image, all of it without tiling.
+fn allocate_image(
+    device: &Device,
+    label: &str,
+    shape: ImageShape,
+) -> Result<SharedBuffer, SharedBufferCreateError> {
+    let (width, height) = shape.tile_extent();
+    device.allocate_shared_buffers(&SharedBufferDescriptor {
+        label: Some(label),
+        size: shape.format.buffer_size(width, height),
+        usage: BufferUsages::STORAGE,
+        memory_location: MemoryLocation::Auto,
+    })
+}
+
+fn check_sizes(
+    inputs: &[(&wgpu::Texture, &SharedBuffer)],
+    output: Option<&wgpu::Texture>,
+    shape: ImageShape,
+) -> Result<(), DenoiseError> {
+    let expected = (shape.width, shape.height);
+    for texture in inputs.iter().map(|(texture, _)| *texture).chain(output) {
+        let actual = (texture.width(), texture.height());
+        if expected != actual {
+            return Err(DenoiseError::SizeMismatch { expected, actual });
+        }
+    }
+    Ok(())
+}
+
+/// Records the conversion of the input textures into their buffers.
+fn encode_inputs(
+    device: &Device,
+    encoder: &mut wgpu::CommandEncoder,
+    inputs: &[(&wgpu::Texture, &SharedBuffer)],
+    options: &ConversionOptions,
+) -> Result<(), DenoiseError> {
+    for (texture, buffer) in inputs {
+        device.encode_texture_to_buffer(
+            encoder,
+            texture,
+            buffer.checked_wgpu_buffer()?.slice(..),
+            options,
+        )?;
+    }
+    Ok(())
+}
+
+/// Denoises `inputs` into `output` one tile after the other, through the shared buffers holding
+/// a tile each.
+///
+/// Every tile's inputs are converted on the GPU, and the submission also holds the conversion of
+/// the previous tile's output, which is cropped to the part of the image the tile is kept for.
+fn denoise_tiles(
+    device: &Device,
+    tiles: &[Tile],
+    job: &FilterJob,
+    inputs: &[(&wgpu::Texture, &SharedBuffer)],
+    output: (&wgpu::Texture, &SharedBuffer),
+) -> Result<(), DenoiseError> {
+    // later tiles read the overlap with the earlier ones
+    if tiles.len() > 1 && inputs.iter().any(|(texture, _)| *texture == output.0) {
+        return Err(DenoiseError::OutputIsInput);
+    }
+    let options = job.shape.conversion_options();
+    let buffers: Vec<_> = inputs.iter().map(|(_, buffer)| *buffer).collect();
+    let mut encoder = device
+        .wgpu_device
+        .create_command_encoder(&Default::default());
+    for tile in tiles {
+        // OIDN ran synchronously, so it is done with the previous tile
+        for (texture, buffer) in inputs {
+            buffer.owner.acquire_wgpu()?;
+            device.encode_texture_region_to_buffer(
+                &mut encoder,
+                texture,
+                tile.input_region(),
+                buffer.checked_wgpu_buffer()?.slice(..),
+                &options,
+            )?;
+        }
+        let submission = device.queue.submit([encoder.finish()]);
+        // the conversion of the previous tile's result was in the same submission
+        wait_for_inputs(device, &buffers, output.1, submission)?;
+        // a single tile stays bound from when the filters were committed
+        job.run(
+            &device.oidn_device,
+            (tiles.len() > 1).then_some(tile.input.size),
+        )?;
+
+        encoder = device
+            .wgpu_device
+            .create_command_encoder(&Default::default());
+        device.wait_for_oidn(output.1)?;
+        output.1.owner.acquire_wgpu()?;
+        device.encode_buffer_region_to_texture(
+            &mut encoder,
+            output.1.checked_wgpu_buffer()?.slice(..),
+            tile.output_region(),
+            output.0,
+            &options,
+        )?;
+    }
+    device.queue.submit([encoder.finish()]);
+    Ok(())
+}
+
+/// Waits until wgpu finished `submission`, which converted the inputs into `buffers`, and hands
+/// them to OIDN together with `output`, whose last conversion was submitted before.
+fn wait_for_inputs(
+    device: &Device,
+    buffers: &[&SharedBuffer],
+    output: &SharedBuffer,
+    submission: wgpu::SubmissionIndex,
+) -> Result<(), DenoiseError> {
+    for buffer in buffers.iter().chain([&output]) {
+        buffer.owner.release_oidn()?;
+    }
+    for buffer in buffers {
+        device.wait_for_wgpu(buffer, Some(submission.clone()))?;
+    }
+    for buffer in buffers.iter().chain([&output]) {
+        buffer.owner.acquire_oidn()?;
+    }
+    Ok(())
+}
+
+/// Waits until OIDN is done, hands the output buffer to wgpu and records its conversion into the
+/// output texture.
+fn convert_output(
+    device: &Device,
+    encoder: &mut wgpu::CommandEncoder,
+    output: (&wgpu::Texture, &SharedBuffer),
+    options: &ConversionOptions,
+) -> Result<(), DenoiseError> {
+    device.wait_for_oidn(output.1)?;
+    output.1.owner.acquire_wgpu()?;
+    device.encode_buffer_to_texture(
+        encoder,
+        output.1.checked_wgpu_buffer()?.slice(..),
+        output.0,
+        options,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_tiles_cover_image() {
+    let shape = |width, height, tile_size| ImageShape {
+        format: BufferFormat::Float3,
+        width,
+        height,
+        tile_size,
+    };
+    for (shape, overlap, alignment, count) in [
+        (shape(100, 50, None), 16, 16, 1),
+        (shape(100, 50, Some(100)), 16, 16, 1),
+        (shape(1000, 600, Some(256)), 32, 16, 6 * 4),
+        (shape(1001, 257, Some(256)), 0, 1, 4 * 2),
+        (shape(333, 333, Some(97)), 10, 8, 6 * 6),
+    ] {
+        let tiles = tiles(shape, overlap, alignment).unwrap();
+        assert_eq!(tiles.len(), count);
+        let (tile_width, tile_height) = shape.tile_extent();
+        let mut covered = vec![0; (shape.width * shape.height) as usize];
+        for tile in tiles {
+            let input = tile.input;
+            let output = tile.output;
+            assert!(input.size[0] <= tile_width && input.size[1] <= tile_height);
+            for axis in 0..2 {
+                let size = [shape.width, shape.height][axis];
+                let start = input.origin[axis];
+                let end = start + input.size[axis];
+                assert!(end <= size);
+                // the filters need their tiles aligned
+                assert_eq!(start % alignment, 0);
+                assert!(output.origin[axis] >= start);
+                // the output has the overlap as context, unless it is at the border
+                let before = output.origin[axis] - start;
+                let after = end - (output.origin[axis] + output.size[axis]);
+                assert!(before >= overlap || start == 0);
+                assert!(after >= overlap || end == size);
+            }
+            for y in 0..output.size[1] {
+                for x in 0..output.size[0] {
+                    let x = output.origin[0] + x;
+                    let y = output.origin[1] + y;
+                    covered[(y * shape.width + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|count| *count == 1));
+    }
+    assert!(matches!(
+        tiles(shape(1000, 1000, Some(64)), 32, 16),
+        Err(DenoiseError::TileTooSmall { .. })
+    ));
+}
@@ -2,62 +2,179 @@ use oidn::sys::OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_
 use std::ptr;
 use wgpu::hal::api::Dx12;
 use wgpu::hal::{CommandEncoder, dx12};
-use wgpu::{BufferDescriptor, BufferUsages, DeviceDescriptor};
-use windows::Win32::Foundation::GENERIC_ALL;
+use wgpu::{BufferDescriptor, BufferUsages};
+use windows::Win32::Foundation::{GENERIC_ALL, LUID};
 use windows::Win32::Graphics::Direct3D12::{
-    D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE, D3D12_DEFAULT_RESOURCE_PLACEMENT_ALIGNMENT,
-    D3D12_HEAP_DESC, D3D12_HEAP_FLAG_SHARED, D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER,
-    D3D12_HEAP_PROPERTIES, D3D12_HEAP_TYPE_CUSTOM, D3D12_MEMORY_POOL_L0, D3D12_RESOURCE_DESC,
-    D3D12_RESOURCE_DIMENSION_BUFFER, D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER,
-    D3D12_RESOURCE_STATE_COMMON, D3D12_TEXTURE_LAYOUT_ROW_MAJOR, ID3D12Heap,
+    D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE, D3D12_CPU_PAGE_PROPERTY_WRITE_BACK,
+    D3D12_DEFAULT_RESOURCE_PLACEMENT_ALIGNMENT, D3D12_HEAP_DESC, D3D12_HEAP_FLAG_SHARED,
+    D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER, D3D12_HEAP_PROPERTIES, D3D12_HEAP_TYPE_CUSTOM,
+    D3D12_MEMORY_POOL_L0, D3D12_PLACED_SUBRESOURCE_FOOTPRINT, D3D12_RESOURCE_DESC,
+    D3D12_RESOURCE_DIMENSION_BUFFER, D3D12_RESOURCE_DIMENSION_TEXTURE2D,
+    D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER, D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS,
+    D3D12_RESOURCE_FLAGS, D3D12_RESOURCE_STATE_COMMON, D3D12_TEXTURE_LAYOUT_ROW_MAJOR, ID3D12Heap,
+    ID3D12Resource,
 };
-use windows::Win32::Graphics::Dxgi::Common::{DXGI_FORMAT_UNKNOWN, DXGI_SAMPLE_DESC};
+use windows::Win32::Graphics::Dxgi::Common::{
+    DXGI_FORMAT_R16G16B16A16_FLOAT, DXGI_FORMAT_R32G32B32A32_FLOAT, DXGI_FORMAT_UNKNOWN,
+    DXGI_SAMPLE_DESC,
+};
+use windows::core::HSTRING;
 
 pub(crate) struct Dx12Allocation {
     _heap: ID3D12Heap,
 }
 
+pub(crate) fn adapter_luid(adapter: &wgpu::Adapter) -> Result<LUID, crate::DeviceCreateError> {
+    // # SAFETY: the raw handle is not manually destroyed.
+    let adapter_dx12_desc = unsafe {
+        let adapter = adapter.as_hal::<Dx12>();
+        adapter.map(|adapter| adapter.raw_adapter().GetDesc2().unwrap())
+    };
+    adapter_dx12_desc.map(|desc| desc.AdapterLuid).ok_or(
+        crate::DeviceCreateError::UnsupportedBackend(adapter.get_info().backend),
+    )
+}
+
+/// Translates wgpu usages into the resource flags of a shared buffer.
+fn resource_flags(usage: BufferUsages, cross_adapter: bool) -> D3D12_RESOURCE_FLAGS {
+    let mut flags = D3D12_RESOURCE_FLAGS::default();
+    if cross_adapter {
+        flags |= D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER;
+    }
+    // wgpu binds indirect buffers as storage to validate the arguments, and query resolve
+    // buffers to normalise timestamps
+    if usage
+        .intersects(BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::QUERY_RESOLVE)
+    {
+        flags |= D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS;
+    }
+    flags
+}
+
+/// Translates wgpu usages into the resource flags of a shared texture.
+fn texture_resource_flags(usage: wgpu::TextureUsages) -> D3D12_RESOURCE_FLAGS {
+    let mut flags = D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER;
+    if usage.contains(wgpu::TextureUsages::STORAGE_BINDING) {
+        flags |= D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS;
+    }
+    flags
+}
+
 impl crate::Device {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(adapter = %adapter.get_info().name))
+    )]
     pub(crate) async fn new_dx12(
         adapter: &wgpu::Adapter,
-        desc: &DeviceDescriptor<'_>,
+        builder: &crate::DeviceBuilder<'_>,
     ) -> Result<(Self, wgpu::Queue), crate::DeviceCreateError> {
+        let (oidn_device, backend_data) = Self::dx12_oidn_device(adapter, builder)?;
+        let (wgpu_device, queue) = adapter.request_device(&builder.desc).await?;
+        Ok((
+            Self {
+                wgpu_device,
+                oidn_device: oidn_device.into(),
+                queue: queue.clone(),
+                backend_data,
+                converter: Default::default(),
+                worker: Default::default(),
+            },
+            queue,
+        ))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(adapter = %adapter.get_info().name))
+    )]
+    pub(crate) fn from_existing_dx12(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+        wgpu_device: wgpu::Device,
+        queue: wgpu::Queue,
+    ) -> Result<Self, crate::DeviceCreateError> {
         // # SAFETY: the raw handle is not manually destroyed.
-        let adapter_dx12_desc = unsafe {
-            let adapter = adapter.as_hal::<Dx12>();
-            adapter.map(|adapter| adapter.raw_adapter().GetDesc2().unwrap())
-        };
-        let Some(dx_desc) = adapter_dx12_desc else {
-            return Err(crate::DeviceCreateError::UnsupportedBackend(
-                adapter.get_info().backend,
-            ));
+        let device_luid = unsafe {
+            let device = wgpu_device.as_hal::<Dx12>();
+            device.map(|device| device.raw_device().GetAdapterLuid())
         };
-        let device =
-            unsafe { oidn::sys::oidnNewDeviceByLUID((&dx_desc.AdapterLuid) as *const _ as _) };
-        Self::new_from_raw_oidn_adapter(device, adapter, desc, |flag| {
+        if device_luid != Some(adapter_luid(adapter)?) {
+            return Err(crate::DeviceCreateError::AdapterMismatch);
+        }
+        let (oidn_device, backend_data) = Self::dx12_oidn_device(adapter, builder)?;
+        Ok(Self {
+            wgpu_device,
+            oidn_device: oidn_device.into(),
+            queue,
+            backend_data,
+            converter: Default::default(),
+            worker: Default::default(),
+        })
+    }
+
+    pub(crate) fn probe_dx12(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+        capabilities: &mut crate::InteropCapabilities,
+    ) -> Result<(), crate::DeviceCreateError> {
+        let luid = adapter_luid(adapter)?;
+        let device = unsafe { oidn::sys::oidnNewDeviceByLUID((&luid) as *const _ as _) };
+        if !device.is_null() {
+            capabilities.id_match = Some(crate::DeviceIdMatch::Luid);
+        }
+        let (oidn_device, flag) = Self::oidn_device_from_raw(device, builder, Ok)?;
+        capabilities.oidn_device_type = Some(crate::OidnDeviceType::of(&oidn_device));
+        capabilities.oidn_external_memory_types = flag;
+        if flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32 == 0 {
+            return Err(crate::DeviceCreateError::OidnImportUnsupported);
+        }
+        Ok(())
+    }
+
+    fn dx12_oidn_device(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+    ) -> Result<(oidn::Device, crate::BackendData), crate::DeviceCreateError> {
+        let luid = adapter_luid(adapter)?;
+        let device = unsafe { oidn::sys::oidnNewDeviceByLUID((&luid) as *const _ as _) };
+        Self::oidn_device_from_raw(device, builder, |flag| {
             (flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32 != 0)
                 .then_some(crate::BackendData::Dx12)
+                .ok_or(crate::DeviceCreateError::OidnImportUnsupported)
         })
-        .await
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     pub(crate) fn allocate_shared_buffers_dx12(
         &self,
-        size: wgpu::BufferAddress,
+        desc: &crate::SharedBufferDescriptor<'_>,
     ) -> Result<crate::SharedBuffer, crate::SharedBufferCreateError> {
+        let size = desc.size;
         debug_assert_eq!(self.backend_data.as_backend(), crate::Backend::Dx12);
 
         // # SAFETY: the raw handle is not manually destroyed.
         let device = unsafe { self.wgpu_device.as_hal::<Dx12>() };
         unsafe {
             let device = device.unwrap();
+            let host_visible = desc.memory_location == crate::MemoryLocation::HostVisible;
             let properties = D3D12_HEAP_PROPERTIES {
                 Type: D3D12_HEAP_TYPE_CUSTOM,
-                CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE,
+                CPUPageProperty: if host_visible {
+                    D3D12_CPU_PAGE_PROPERTY_WRITE_BACK
+                } else {
+                    D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE
+                },
                 MemoryPoolPreference: D3D12_MEMORY_POOL_L0,
                 CreationNodeMask: 0,
                 VisibleNodeMask: 0,
             };
-            let flags = D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER | D3D12_HEAP_FLAG_SHARED;
+            // cross adapter heaps can't be accessed by the CPU
+            let flags = if host_visible {
+                D3D12_HEAP_FLAG_SHARED
+            } else {
+                D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER | D3D12_HEAP_FLAG_SHARED
+            };
             let heap_desc = D3D12_HEAP_DESC {
                 SizeInBytes: size,
                 Properties: properties,
@@ -72,11 +189,13 @@ impl crate::Device {
                 .raw_device()
                 .CreateHeap(&heap_desc, &mut heap)
                 .map_err(|err| {
-                    eprintln!("Failed to create heap: {}", err.message());
-                    crate::SharedBufferCreateError::OutOfMemory
+                    crate::SharedBufferCreateError::backend(
+                        crate::AllocationStep::AllocateMemory,
+                        err,
+                    )
                 })?;
             let heap: ID3D12Heap = heap.unwrap();
-            let desc = D3D12_RESOURCE_DESC {
+            let resource_desc = D3D12_RESOURCE_DESC {
                 Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
                 Alignment: 0,
                 Width: size,
@@ -89,7 +208,7 @@ impl crate::Device {
                     Quality: 0,
                 },
                 Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
-                Flags: D3D12_RESOURCE_FLAG_ALLOW_CROSS_ADAPTER,
+                Flags: resource_flags(desc.usage, !host_visible),
             };
             let mut resource = None;
             device
@@ -97,37 +216,25 @@ impl crate::Device {
                 .CreatePlacedResource(
                     &heap,
                     0,
-                    &desc,
+                    &resource_desc,
                     D3D12_RESOURCE_STATE_COMMON,
                     None,
                     &mut resource,
                 )
                 .map_err(|err| {
-                    eprintln!("Failed to create resource: {}", err.message());
-                    crate::SharedBufferCreateError::OutOfMemory
+                    crate::SharedBufferCreateError::backend(
+                        crate::AllocationStep::CreateBuffer,
+                        err,
+                    )
                 })?;
-            let resource = resource.unwrap();
-            let handle = device
-                .raw_device()
-                .CreateSharedHandle(&heap, None, GENERIC_ALL.0, None)
-                .map_err(|err| {
-                    eprintln!("Failed to create shared handle: {}", err.message());
-                    crate::SharedBufferCreateError::OutOfMemory
-                })?;
-            let oidn_buffer = oidn::sys::oidnNewSharedBufferFromWin32Handle(
-                self.oidn_device.raw(),
-                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
-                handle.0,
-                ptr::null(),
-                size as usize,
-            );
-            if oidn_buffer.is_null() {
-                eprintln!("Failed to create oidn buffer");
-                eprintln!("error: {:?}", self.oidn_device.get_error());
-                return Err(crate::SharedBufferCreateError::Oidn(
-                    self.oidn_device.get_error().unwrap_err(),
-                ));
+            let resource: ID3D12Resource = resource.unwrap();
+            if let Some(label) = desc.label {
+                let name = HSTRING::from(label);
+                // Names only help debugging tools, so failing to set one is not an error.
+                let _ = heap.SetName(&name);
+                let _ = resource.SetName(&name);
             }
+            let oidn_buffer = self.export_heap_to_oidn(&device, &heap, size)?;
             let buf = dx12::Device::buffer_from_raw(resource, size);
             // # SAFETY: the raw handle is not manually destroyed.
             let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
@@ -140,19 +247,181 @@ impl crate::Device {
             let wgpu_buffer = self.wgpu_device.create_buffer_from_hal::<Dx12>(
                 buf,
                 &BufferDescriptor {
-                    label: None,
+                    label: desc.label,
                     size,
-                    usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+                    usage: desc.usage,
                     mapped_at_creation: false,
                 },
             );
             Ok(crate::SharedBuffer {
-                _allocation: crate::Allocation::Dx12 {
+                allocation: crate::Allocation::Dx12 {
                     _dx12: Dx12Allocation { _heap: heap },
                 },
                 wgpu_buffer,
-                oidn_buffer: self.oidn_device.create_buffer_from_raw(oidn_buffer),
+                oidn_buffer,
+                owner: Default::default(),
+                device: self.clone(),
             })
         }
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub(crate) fn allocate_shared_texture_dx12(
+        &self,
+        desc: &crate::SharedTextureDescriptor<'_>,
+    ) -> Result<crate::SharedTexture, crate::SharedBufferCreateError> {
+        debug_assert_eq!(self.backend_data.as_backend(), crate::Backend::Dx12);
+
+        // # SAFETY: the raw handle is not manually destroyed.
+        let device = unsafe { self.wgpu_device.as_hal::<Dx12>() };
+        unsafe {
+            let device = device.unwrap();
+            let format = match desc.format {
+                wgpu::TextureFormat::Rgba32Float => DXGI_FORMAT_R32G32B32A32_FLOAT,
+                wgpu::TextureFormat::Rgba16Float => DXGI_FORMAT_R16G16B16A16_FLOAT,
+                _ => unreachable!("format was validated"),
+            };
+            // only row major textures can be shared across adapters, which is also what lets
+            // OIDN address the texels
+            let resource_desc = D3D12_RESOURCE_DESC {
+                Dimension: D3D12_RESOURCE_DIMENSION_TEXTURE2D,
+                Alignment: 0,
+                Width: desc.width as u64,
+                Height: desc.height,
+                DepthOrArraySize: 1,
+                MipLevels: 1,
+                Format: format,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    Quality: 0,
+                },
+                Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
+                Flags: texture_resource_flags(desc.usage),
+            };
+            let allocation_info = device
+                .raw_device()
+                .GetResourceAllocationInfo(0, &[resource_desc]);
+            let heap_desc = D3D12_HEAP_DESC {
+                SizeInBytes: allocation_info.SizeInBytes,
+                Properties: D3D12_HEAP_PROPERTIES {
+                    Type: D3D12_HEAP_TYPE_CUSTOM,
+                    CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_NOT_AVAILABLE,
+                    MemoryPoolPreference: D3D12_MEMORY_POOL_L0,
+                    CreationNodeMask: 0,
+                    VisibleNodeMask: 0,
+                },
+                Alignment: allocation_info.Alignment,
+                Flags: D3D12_HEAP_FLAG_SHARED_CROSS_ADAPTER | D3D12_HEAP_FLAG_SHARED,
+            };
+            let mut heap = None;
+            device
+                .raw_device()
+                .CreateHeap(&heap_desc, &mut heap)
+                .map_err(|err| {
+                    crate::SharedBufferCreateError::backend(
+                        crate::AllocationStep::AllocateMemory,
+                        err,
+                    )
+                })?;
+            let heap: ID3D12Heap = heap.unwrap();
+            let mut resource = None;
+            device
+                .raw_device()
+                .CreatePlacedResource(
+                    &heap,
+                    0,
+                    &resource_desc,
+                    D3D12_RESOURCE_STATE_COMMON,
+                    None,
+                    &mut resource,
+                )
+                .map_err(|err| {
+                    crate::SharedBufferCreateError::backend(
+                        crate::AllocationStep::CreateBuffer,
+                        err,
+                    )
+                })?;
+            let resource: ID3D12Resource = resource.unwrap();
+            if let Some(label) = desc.label {
+                let name = HSTRING::from(label);
+                // Names only help debugging tools, so failing to set one is not an error.
+                let _ = heap.SetName(&name);
+                let _ = resource.SetName(&name);
+            }
+            let mut footprint = D3D12_PLACED_SUBRESOURCE_FOOTPRINT::default();
+            device.raw_device().GetCopyableFootprints(
+                &resource_desc,
+                0,
+                1,
+                0,
+                Some(&mut footprint),
+                None,
+                None,
+                None,
+            );
+            let layout = crate::SharedTextureLayout::new(
+                desc,
+                footprint.Offset,
+                u64::from(footprint.Footprint.RowPitch),
+            );
+            let oidn_buffer =
+                self.export_heap_to_oidn(&device, &heap, allocation_info.SizeInBytes)?;
+            let texture = dx12::Device::texture_from_raw(
+                resource,
+                desc.format,
+                wgpu::TextureDimension::D2,
+                desc.size(),
+                1,
+                1,
+            );
+            // # SAFETY: the texture was created from the same device with the descriptor's
+            // format, size and usages.
+            let wgpu_texture = self
+                .wgpu_device
+                .create_texture_from_hal::<Dx12>(texture, &desc.wgpu_descriptor());
+            Ok(crate::SharedTexture {
+                _allocation: crate::Allocation::Dx12 {
+                    _dx12: Dx12Allocation { _heap: heap },
+                },
+                oidn_buffer,
+                wgpu_texture,
+                layout,
+                owner: Default::default(),
+                device: self.clone(),
+            })
+        }
+    }
+
+    /// Shares `heap` and imports its first `size` bytes into OIDN.
+    unsafe fn export_heap_to_oidn(
+        &self,
+        device: &dx12::Device,
+        heap: &ID3D12Heap,
+        size: u64,
+    ) -> Result<oidn::Buffer, crate::SharedBufferCreateError> {
+        unsafe {
+            let handle = device
+                .raw_device()
+                .CreateSharedHandle(heap, None, GENERIC_ALL.0, None)
+                .map_err(|err| {
+                    crate::SharedBufferCreateError::backend(
+                        crate::AllocationStep::ExportHandle,
+                        err,
+                    )
+                })?;
+            let oidn_buffer = oidn::sys::oidnNewSharedBufferFromWin32Handle(
+                self.oidn_device.raw(),
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
+                handle.0,
+                ptr::null(),
+                size as usize,
+            );
+            if oidn_buffer.is_null() {
+                let err = self.oidn_device.get_error().unwrap_err();
+                trace_event!(warn, "Failed to create oidn buffer: {:?}", err);
+                return Err(crate::SharedBufferCreateError::Oidn(err));
+            }
+            Ok(self.oidn_device.create_buffer_from_raw(oidn_buffer))
+        }
+    }
 }
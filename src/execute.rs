@@ -0,0 +1,167 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::{DenoiseError, Device};
+
+#[derive(Default)]
+struct ExecutionState {
+    /// The result, until the future takes it.
+    result: Option<Result<(), DenoiseError>>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct Execution {
+    state: Mutex<ExecutionState>,
+    finished: Condvar,
+}
+
+impl Execution {
+    fn finish(&self, result: Result<(), DenoiseError>) {
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.result = Some(result);
+            state.finished = true;
+            state.waker.take()
+        };
+        self.finished.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves once the work started by
+/// [`Denoiser::execute_async`](crate::Denoiser::execute_async) has finished.
+///
+/// The work runs whether or not this is polled.
+#[must_use = "the filters' errors are only reported through the future"]
+pub struct FilterExecution {
+    execution: Arc<Execution>,
+    /// Whether the result was returned already.
+    completed: bool,
+}
+
+impl Future for FilterExecution {
+    type Output = Result<(), DenoiseError>;
+
+    /// Panics if polled again after returning the result, like other futures.
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.completed, "`FilterExecution` polled after completion");
+        let result = {
+            let mut state = self.execution.state.lock().unwrap();
+            match state.result.take() {
+                Some(result) => result,
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        };
+        self.completed = true;
+        Poll::Ready(result)
+    }
+}
+
+/// Blocks until the work behind a [`FilterExecution`] has finished, without taking its result.
+pub(crate) struct ExecutionWaiter {
+    execution: Arc<Execution>,
+}
+
+impl ExecutionWaiter {
+    pub(crate) fn wait(&self) {
+        let state = self.execution.state.lock().unwrap();
+        let _state = self
+            .execution
+            .finished
+            .wait_while(state, |state| !state.finished)
+            .unwrap();
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The thread of a [`Device`] that runs the work of
+/// [`Denoiser::execute_async`](crate::Denoiser::execute_async), one job after the other.
+///
+/// It is shared by the clones of the device and exits once they are all dropped and the queued
+/// work is done.
+pub(crate) struct Worker {
+    /// [`None`] if the thread couldn't be spawned.
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl Worker {
+    fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let spawned = thread::Builder::new()
+            .name("oidn-wgpu-interop worker".to_owned())
+            .spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            });
+        match spawned {
+            Ok(_) => Self {
+                sender: Some(sender),
+            },
+            Err(err) => {
+                trace_event!(warn, "Failed to spawn the OIDN worker thread: {}", err);
+                Self { sender: None }
+            }
+        }
+    }
+}
+
+impl Device {
+    /// Runs `work` on the device's worker thread, so that the calling thread can keep recording
+    /// wgpu work.
+    pub(crate) fn spawn_execution(
+        &self,
+        work: impl FnOnce() -> Result<(), DenoiseError> + Send + 'static,
+    ) -> (FilterExecution, ExecutionWaiter) {
+        let execution = Arc::new(Execution::default());
+        let worker_execution = execution.clone();
+        let job: Job = Box::new(move || worker_execution.finish(work()));
+        let worker = self.worker.get_or_init(Worker::spawn);
+        // sending only fails if the thread is gone, the job comes back with the error
+        let sent = match &worker.sender {
+            Some(sender) => sender.send(job).map_err(|err| err.0),
+            None => Err(job),
+        };
+        if let Err(job) = sent {
+            // run it here instead
+            job();
+        }
+        (
+            FilterExecution {
+                execution: execution.clone(),
+                completed: false,
+            },
+            ExecutionWaiter { execution },
+        )
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_poll_after_completion() {
+    let execution = Arc::new(Execution::default());
+    let mut future = FilterExecution {
+        execution: execution.clone(),
+        completed: false,
+    };
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+    execution.finish(Ok(()));
+    assert!(matches!(
+        Pin::new(&mut future).poll(&mut cx),
+        Poll::Ready(Ok(()))
+    ));
+    let result = std::panic::catch_unwind(move || Pin::new(&mut future).poll(&mut cx).is_ready());
+    assert!(result.is_err());
+}
@@ -0,0 +1,171 @@
+use glow::HasContext;
+use wgpu::hal::api::Gles;
+use wgpu::hal::gles::AdapterContext;
+
+use crate::StagingMode;
+
+/// The extensions that expose the device UUID and external memory objects.
+const MEMORY_OBJECT_EXTENSIONS: [&str; 2] = ["GL_EXT_memory_object", "GL_EXT_memory_object_fd"];
+
+const GL_NUM_DEVICE_UUIDS_EXT: u32 = 0x9596;
+const GL_DEVICE_UUID_EXT: u32 = 0x9597;
+const GL_UUID_SIZE_EXT: usize = 16;
+
+type GetUnsignedBytei = unsafe extern "system" fn(target: u32, index: u32, data: *mut u8);
+
+/// The parts of a GL adapter that decide which OIDN device it is paired with.
+///
+/// wgpu's GL backend can't create a buffer from an imported memory object, so even when
+/// `GL_EXT_memory_object_fd` is supported buffers are copied through the CPU (see
+/// [`StagingMode`]). The extensions are still used to find the OIDN device on the same GPU.
+pub(crate) struct GlAdapterSupport {
+    /// Memory object extensions the context supports.
+    pub(crate) supported_extensions: Vec<&'static str>,
+    pub(crate) device_uuid: Option<[u8; GL_UUID_SIZE_EXT]>,
+}
+
+impl GlAdapterSupport {
+    fn query(context: &AdapterContext) -> Self {
+        let gl = context.lock();
+        let supported_extensions: Vec<_> = MEMORY_OBJECT_EXTENSIONS
+            .into_iter()
+            .filter(|extension| gl.supported_extensions().contains(*extension))
+            .collect();
+        let mut support = Self {
+            supported_extensions,
+            device_uuid: None,
+        };
+        if !support
+            .supported_extensions
+            .contains(&MEMORY_OBJECT_EXTENSIONS[0])
+        {
+            return support;
+        }
+
+        // glow doesn't load `glGetUnsignedBytei_vEXT`
+        let get_unsigned_bytei = context
+            .egl_instance()
+            .and_then(|egl| egl.get_proc_address("glGetUnsignedBytei_vEXT"));
+        if let Some(get_unsigned_bytei) = get_unsigned_bytei
+            && unsafe { gl.get_parameter_i32(GL_NUM_DEVICE_UUIDS_EXT) } > 0
+        {
+            // # SAFETY: this is the signature given by GL_EXT_memory_object.
+            let get_unsigned_bytei: GetUnsignedBytei =
+                unsafe { std::mem::transmute(get_unsigned_bytei) };
+            let mut uuid = [0; GL_UUID_SIZE_EXT];
+            unsafe { get_unsigned_bytei(GL_DEVICE_UUID_EXT, 0, uuid.as_mut_ptr()) };
+            support.device_uuid = Some(uuid);
+        }
+        support
+    }
+
+    fn from_adapter(adapter: &wgpu::Adapter) -> Result<Self, crate::DeviceCreateError> {
+        // # SAFETY: the raw handle is not manually destroyed.
+        let Some(adapter) = (unsafe { adapter.as_hal::<Gles>() }) else {
+            return Err(crate::DeviceCreateError::UnsupportedBackend(
+                adapter.get_info().backend,
+            ));
+        };
+        Ok(Self::query(adapter.adapter_context()))
+    }
+
+    /// Creates the OIDN device on the same GPU, or the default device if it can't be found.
+    fn new_oidn_device(&self) -> (oidn::sys::OIDNDevice, Option<crate::DeviceIdMatch>) {
+        unsafe {
+            if let Some(uuid) = &self.device_uuid {
+                let dev_raw = oidn::sys::oidnNewDeviceByUUID(uuid as *const _ as *const _);
+                if !dev_raw.is_null() {
+                    return (dev_raw, Some(crate::DeviceIdMatch::Uuid));
+                }
+            }
+            let dev_raw =
+                oidn::sys::oidnNewDevice(oidn::sys::OIDNDeviceType_OIDN_DEVICE_TYPE_DEFAULT);
+            (dev_raw, None)
+        }
+    }
+}
+
+/// Fails if the builder only allows zero-copy devices, which the GL backend can't create.
+fn check_staging(builder: &crate::DeviceBuilder<'_>) -> Result<(), crate::DeviceCreateError> {
+    if builder.staging == StagingMode::Never {
+        trace_event!(
+            debug,
+            "wgpu's GL backend can't import memory, a staging mode is required"
+        );
+        return Err(crate::DeviceCreateError::MissingFeature);
+    }
+    Ok(())
+}
+
+impl crate::Device {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(adapter = %adapter.get_info().name))
+    )]
+    pub(crate) async fn new_gl(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+    ) -> Result<(Self, wgpu::Queue), crate::DeviceCreateError> {
+        let support = GlAdapterSupport::from_adapter(adapter)?;
+        let oidn_device = Self::gl_oidn_device(&support, builder)?;
+        let (wgpu_device, queue) = adapter.request_device(&builder.desc).await?;
+        Ok((
+            Self {
+                wgpu_device,
+                oidn_device: oidn_device.into(),
+                queue: queue.clone(),
+                backend_data: crate::BackendData::Staging,
+                converter: Default::default(),
+                worker: Default::default(),
+            },
+            queue,
+        ))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub(crate) fn from_existing_gl(
+        builder: &crate::DeviceBuilder<'_>,
+        wgpu_device: wgpu::Device,
+        queue: wgpu::Queue,
+    ) -> Result<Self, crate::DeviceCreateError> {
+        // # SAFETY: the raw handle is not manually destroyed.
+        let support = unsafe { wgpu_device.as_hal::<Gles>() }
+            .map(|device| GlAdapterSupport::query(device.context()))
+            .ok_or(crate::DeviceCreateError::AdapterMismatch)?;
+        let oidn_device = Self::gl_oidn_device(&support, builder)?;
+        Ok(Self {
+            wgpu_device,
+            oidn_device: oidn_device.into(),
+            queue,
+            backend_data: crate::BackendData::Staging,
+            converter: Default::default(),
+            worker: Default::default(),
+        })
+    }
+
+    fn gl_oidn_device(
+        support: &GlAdapterSupport,
+        builder: &crate::DeviceBuilder<'_>,
+    ) -> Result<oidn::Device, crate::DeviceCreateError> {
+        check_staging(builder)?;
+        let (device, _) = support.new_oidn_device();
+        Self::oidn_device_from_raw(device, builder, |_| Ok(())).map(|(device, ())| device)
+    }
+
+    pub(crate) fn probe_gl(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+        capabilities: &mut crate::InteropCapabilities,
+    ) -> Result<(), crate::DeviceCreateError> {
+        let support = GlAdapterSupport::from_adapter(adapter)?;
+        capabilities.gl_extensions = support.supported_extensions.clone();
+        capabilities.staging = true;
+        check_staging(builder)?;
+        let (device, id_match) = support.new_oidn_device();
+        capabilities.id_match = id_match;
+        let (oidn_device, flag) = Self::oidn_device_from_raw(device, builder, Ok)?;
+        capabilities.oidn_device_type = Some(crate::OidnDeviceType::of(&oidn_device));
+        capabilities.oidn_external_memory_types = flag;
+        Ok(())
+    }
+}
@@ -1,22 +1,85 @@
-use std::fmt::Debug;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt::{self, Debug, Display};
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
 
+use wgpu::BufferUsages;
+
+// Forwards to the matching `tracing` macro when the `tracing` feature is enabled and compiles to
+// nothing otherwise, so that the library never writes to stderr by itself.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+        // keeps the arguments used
+        #[cfg(not(feature = "tracing"))]
+        {
+            if false {
+                let _ = format_args!($($arg)+);
+            }
+        }
+    };
+}
+
+mod convert;
+mod denoise;
 #[cfg(dx12)]
 mod dx12;
+mod execute;
+#[cfg(gl)]
+mod gl;
+mod pool;
+mod probe;
+mod select;
+mod staging;
+mod sync;
+mod texture;
 #[cfg(vulkan)]
 mod vulkan;
 
+pub use convert::{BufferFormat, ConversionError, ConversionOptions};
+pub use denoise::{
+    AuxMode, DenoiseError, DenoiseInputs, Denoiser, DenoiserDescriptor, DenoiserSettings,
+    LightmapDenoiser, LightmapDenoiserDescriptor, LightmapSettings,
+};
+pub use execute::FilterExecution;
+pub use pool::{SharedBufferPool, SharedBufferSlice};
+pub use probe::{DeviceIdMatch, InteropCapabilities, OidnDeviceType, probe};
+pub use select::{AdapterReport, AdapterSelection, NoSuitableAdapter};
+pub use staging::{StagingMode, SyncError};
+pub use sync::{
+    AccessError, OidnAccess, ReleasedToOidn, ReleasedToWgpu, WgpuAccess, WgpuTextureAccess,
+};
+pub use texture::{SharedTexture, SharedTextureDescriptor, SharedTextureLayout};
+#[cfg(vulkan)]
+pub use vulkan::VulkanSharingMode;
+
 pub enum DeviceCreateError {
     RequestDeviceError(wgpu::RequestDeviceError),
     OidnUnsupported,
     OidnImportUnsupported,
     MissingFeature,
     UnsupportedBackend(wgpu::Backend),
+    /// An existing device lacks the listed device extensions, which it needs for sharing memory.
+    MissingDeviceExtensions(&'static [&'static CStr]),
+    /// An existing device was not created from the given adapter.
+    AdapterMismatch,
+    /// The adapter does not support the features required by the device descriptor.
+    UnsupportedFeatures(wgpu::Features),
+    /// The device descriptor requires limits that are better than the adapter supports.
+    LimitsExceeded,
+    /// The backend failed to open the device.
+    OpenDevice(wgpu::hal::DeviceError),
 }
 
-impl Debug for DeviceCreateError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl Display for DeviceCreateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            DeviceCreateError::RequestDeviceError(err) => err.fmt(f),
+            DeviceCreateError::RequestDeviceError(err) => {
+                f.write_str("wgpu device request failed: ")?;
+                Display::fmt(err, f)
+            }
             DeviceCreateError::OidnUnsupported => f.write_str(
                 "OIDN could not create a device for this Adapter (does this adapter support OIDN?)",
             ),
@@ -26,34 +89,277 @@ impl Debug for DeviceCreateError {
             DeviceCreateError::MissingFeature => f.write_str("A required feature is missing"),
             DeviceCreateError::UnsupportedBackend(backend) => {
                 f.write_str("The backend ")?;
-                backend.fmt(f)?;
+                Display::fmt(backend, f)?;
                 f.write_str(" is not supported.")
             }
+            DeviceCreateError::MissingDeviceExtensions(extensions) => {
+                f.write_str("The device was created without the extensions")?;
+                for extension in *extensions {
+                    f.write_str(" ")?;
+                    f.write_str(&extension.to_string_lossy())?;
+                }
+                f.write_str(" enabled")
+            }
+            DeviceCreateError::AdapterMismatch => {
+                f.write_str("The device was not created from the given adapter")
+            }
+            DeviceCreateError::UnsupportedFeatures(features) => {
+                f.write_str("The adapter does not support the features ")?;
+                Debug::fmt(features, f)
+            }
+            DeviceCreateError::LimitsExceeded => {
+                f.write_str("The required limits exceed the adapter's limits")
+            }
+            DeviceCreateError::OpenDevice(err) => {
+                f.write_str("Failed to open the device: ")?;
+                Display::fmt(err, f)
+            }
+        }
+    }
+}
+
+impl Debug for DeviceCreateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for DeviceCreateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DeviceCreateError::RequestDeviceError(err) => Some(err),
+            DeviceCreateError::OpenDevice(err) => Some(err),
+            DeviceCreateError::OidnUnsupported
+            | DeviceCreateError::OidnImportUnsupported
+            | DeviceCreateError::MissingFeature
+            | DeviceCreateError::UnsupportedBackend(_)
+            | DeviceCreateError::MissingDeviceExtensions(_)
+            | DeviceCreateError::AdapterMismatch
+            | DeviceCreateError::UnsupportedFeatures(_)
+            | DeviceCreateError::LimitsExceeded => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for DeviceCreateError {
+    fn from(err: wgpu::RequestDeviceError) -> Self {
+        DeviceCreateError::RequestDeviceError(err)
+    }
+}
+
+/// An error code returned by the underlying graphics API.
+///
+/// Only exists with a backend that shares memory directly, the others make no backend calls
+/// of their own.
+#[cfg(any(dx12, vulkan))]
+#[derive(Clone, Debug)]
+pub enum BackendError {
+    #[cfg(dx12)]
+    Dx12(windows::core::Error),
+    #[cfg(vulkan)]
+    Vulkan(ash::vk::Result),
+}
+
+#[cfg(any(dx12, vulkan))]
+impl Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(dx12)]
+            BackendError::Dx12(ref err) => {
+                f.write_str("DirectX 12 call failed with ")?;
+                Display::fmt(&err.code(), f)?;
+                f.write_str(": ")?;
+                f.write_str(&err.message())
+            }
+            #[cfg(vulkan)]
+            BackendError::Vulkan(result) => {
+                f.write_str("Vulkan call failed with ")?;
+                Display::fmt(&result, f)
+            }
+        }
+    }
+}
+
+#[cfg(any(dx12, vulkan))]
+impl Error for BackendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(dx12)]
+            BackendError::Dx12(err) => Some(err),
+            #[cfg(vulkan)]
+            BackendError::Vulkan(result) => Some(result),
+        }
+    }
+}
+
+#[cfg(dx12)]
+impl From<windows::core::Error> for BackendError {
+    fn from(err: windows::core::Error) -> Self {
+        BackendError::Dx12(err)
+    }
+}
+
+#[cfg(vulkan)]
+impl From<ash::vk::Result> for BackendError {
+    fn from(result: ash::vk::Result) -> Self {
+        BackendError::Vulkan(result)
+    }
+}
+
+#[cfg(any(dx12, vulkan))]
+impl BackendError {
+    fn is_out_of_memory(&self) -> bool {
+        match *self {
+            #[cfg(dx12)]
+            BackendError::Dx12(ref err) => err.code() == windows::Win32::Foundation::E_OUTOFMEMORY,
+            #[cfg(vulkan)]
+            BackendError::Vulkan(result) => matches!(
+                result,
+                ash::vk::Result::ERROR_OUT_OF_HOST_MEMORY
+                    | ash::vk::Result::ERROR_OUT_OF_DEVICE_MEMORY
+            ),
         }
     }
 }
 
+/// The step of shared buffer allocation that a [`SharedBufferCreateError::Backend`] came from.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum AllocationStep {
+    /// Creating the buffer object (`vkCreateBuffer` / `CreatePlacedResource`).
+    CreateBuffer,
+    /// Allocating the backing memory (`vkAllocateMemory` / `CreateHeap`).
+    AllocateMemory,
+    /// Binding the backing memory to the buffer (`vkBindBufferMemory`).
+    BindMemory,
+    /// Exporting a handle to the memory for OIDN (`vkGetMemoryFdKHR`,
+    /// `vkGetMemoryWin32HandleKHR` / `CreateSharedHandle`).
+    ExportHandle,
+}
+
+impl Display for AllocationStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AllocationStep::CreateBuffer => "creating the buffer",
+            AllocationStep::AllocateMemory => "allocating memory",
+            AllocationStep::BindMemory => "binding memory",
+            AllocationStep::ExportHandle => "exporting the memory handle",
+        })
+    }
+}
+
 pub enum SharedBufferCreateError {
     InvalidSize(wgpu::BufferAddress),
+    /// The requested usages include ones shared buffers can't have, see
+    /// [`SharedBufferDescriptor::SUPPORTED_USAGES`].
+    UnsupportedUsage(wgpu::BufferUsages),
+    /// A [`SharedBufferPool`] has no free range of the requested size left.
+    PoolExhausted(wgpu::BufferAddress),
+    /// Shared textures can't have this format, see
+    /// [`SharedTextureDescriptor::SUPPORTED_FORMATS`].
+    UnsupportedFormat(wgpu::TextureFormat),
+    /// The requested texture usages are not in [`SharedTextureDescriptor::SUPPORTED_USAGES`] or
+    /// the adapter doesn't support them for linear textures of the format.
+    UnsupportedTextureUsage(wgpu::TextureUsages),
+    /// Textures can't be shared on a staging device.
+    StagingUnsupported,
     Oidn((oidn::Error, String)),
+    /// The backend ran out of host or device memory.
     OutOfMemory,
+    /// The device has no memory type in the requested location that can back an exportable
+    /// buffer.
+    NoSuitableMemoryType,
+    /// A backend call failed for a reason other than running out of memory.
+    #[cfg(any(dx12, vulkan))]
+    Backend {
+        step: AllocationStep,
+        error: BackendError,
+    },
 }
 
-impl Debug for SharedBufferCreateError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl SharedBufferCreateError {
+    // Collapses real out of memory errors into `OutOfMemory` so that they can be told apart
+    // from other failures without matching on backend specific codes.
+    #[cfg(any(dx12, vulkan))]
+    fn backend(step: AllocationStep, error: impl Into<BackendError>) -> Self {
+        let error = error.into();
+        trace_event!(warn, "Failed {}: {}", step, error);
+        if error.is_out_of_memory() {
+            SharedBufferCreateError::OutOfMemory
+        } else {
+            SharedBufferCreateError::Backend { step, error }
+        }
+    }
+}
+
+impl Display for SharedBufferCreateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             SharedBufferCreateError::InvalidSize(size) => {
                 f.write_str("Size ")?;
-                size.fmt(f)?;
+                Display::fmt(size, f)?;
                 f.write_str(" is not allowed")
             }
+            SharedBufferCreateError::PoolExhausted(size) => {
+                f.write_str("The pool has no free range of ")?;
+                Display::fmt(size, f)?;
+                f.write_str(" bytes left")
+            }
+            SharedBufferCreateError::UnsupportedUsage(usage) => {
+                f.write_str("Shared buffers can't have the usages ")?;
+                Debug::fmt(usage, f)
+            }
+            SharedBufferCreateError::UnsupportedFormat(format) => {
+                f.write_str("Shared textures can't have the format ")?;
+                Debug::fmt(format, f)
+            }
+            SharedBufferCreateError::UnsupportedTextureUsage(usage) => {
+                f.write_str("Shared textures can't have the usages ")?;
+                Debug::fmt(usage, f)
+            }
+            SharedBufferCreateError::StagingUnsupported => {
+                f.write_str("Textures can't be shared on a staging device")
+            }
             SharedBufferCreateError::Oidn((error, desc)) => {
                 f.write_str("OIDN shared buffer creation failed with error ")?;
-                error.fmt(f)?;
+                Debug::fmt(error, f)?;
                 f.write_str(": ")?;
-                desc.fmt(f)
+                f.write_str(desc)
             }
             SharedBufferCreateError::OutOfMemory => f.write_str("Out of memory"),
+            SharedBufferCreateError::NoSuitableMemoryType => {
+                f.write_str("No memory type in the requested location supports exportable buffers")
+            }
+            #[cfg(any(dx12, vulkan))]
+            SharedBufferCreateError::Backend { step, error } => {
+                f.write_str("Failed ")?;
+                Display::fmt(step, f)?;
+                f.write_str(": ")?;
+                Display::fmt(error, f)
+            }
+        }
+    }
+}
+
+impl Debug for SharedBufferCreateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for SharedBufferCreateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(any(dx12, vulkan))]
+            SharedBufferCreateError::Backend { error, .. } => Some(error),
+            SharedBufferCreateError::InvalidSize(_)
+            | SharedBufferCreateError::UnsupportedUsage(_)
+            | SharedBufferCreateError::PoolExhausted(_)
+            | SharedBufferCreateError::UnsupportedFormat(_)
+            | SharedBufferCreateError::UnsupportedTextureUsage(_)
+            | SharedBufferCreateError::StagingUnsupported
+            | SharedBufferCreateError::Oidn(_)
+            | SharedBufferCreateError::OutOfMemory
+            | SharedBufferCreateError::NoSuitableMemoryType => None,
         }
     }
 }
@@ -64,13 +370,16 @@ enum Backend {
     Dx12,
     #[cfg(vulkan)]
     Vulkan,
+    Staging,
 }
 
+#[derive(Clone, Copy)]
 enum BackendData {
     #[cfg(dx12)]
     Dx12,
     #[cfg(vulkan)]
     Vulkan(vulkan::VulkanSharingMode),
+    Staging,
 }
 
 impl BackendData {
@@ -80,15 +389,228 @@ impl BackendData {
             BackendData::Dx12 => Backend::Dx12,
             #[cfg(vulkan)]
             BackendData::Vulkan(_) => Backend::Vulkan,
+            BackendData::Staging => Backend::Staging,
+        }
+    }
+}
+
+/// Options applied to the OIDN device before it is committed.
+#[derive(Clone, Copy, Debug, Default)]
+struct OidnDeviceOptions {
+    num_threads: Option<i32>,
+    set_affinity: Option<bool>,
+    verbose: Option<i32>,
+}
+
+impl OidnDeviceOptions {
+    /// # Safety
+    /// `device` must be a valid, uncommitted OIDN device.
+    unsafe fn apply(&self, device: oidn::sys::OIDNDevice) {
+        unsafe {
+            if let Some(num_threads) = self.num_threads {
+                oidn::sys::oidnSetDeviceInt(device, b"numThreads\0" as *const _ as _, num_threads);
+            }
+            if let Some(set_affinity) = self.set_affinity {
+                oidn::sys::oidnSetDeviceBool(
+                    device,
+                    b"setAffinity\0" as *const _ as _,
+                    set_affinity,
+                );
+            }
+            if let Some(verbose) = self.verbose {
+                oidn::sys::oidnSetDeviceInt(device, b"verbose\0" as *const _ as _, verbose);
+            }
+        }
+    }
+}
+
+/// Configures how a [`Device`] is created.
+///
+/// [`Device::new`] is the same as `DeviceBuilder::new().device_descriptor(desc).build(adapter)`.
+#[derive(Clone, Debug)]
+pub struct DeviceBuilder<'a> {
+    desc: wgpu::DeviceDescriptor<'a>,
+    oidn_options: OidnDeviceOptions,
+    staging: StagingMode,
+    staging_oidn_device_type: OidnDeviceType,
+    #[cfg(vulkan)]
+    vulkan_sharing_modes: Vec<VulkanSharingMode>,
+}
+
+impl Default for DeviceBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> DeviceBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            desc: wgpu::DeviceDescriptor::default(),
+            oidn_options: OidnDeviceOptions::default(),
+            staging: StagingMode::default(),
+            staging_oidn_device_type: OidnDeviceType::Cpu,
+            #[cfg(vulkan)]
+            vulkan_sharing_modes: VulkanSharingMode::DEFAULT_ORDER.to_vec(),
+        }
+    }
+
+    /// Sets the descriptor passed to `request_device`.
+    pub fn device_descriptor(mut self, desc: wgpu::DeviceDescriptor<'a>) -> Self {
+        self.desc = desc;
+        self
+    }
+
+    /// Tries `mode` before any other sharing mode, falling back to the default order if it is
+    /// not supported by both wgpu and OIDN.
+    #[cfg(vulkan)]
+    pub fn prefer_vulkan_sharing_mode(mut self, mode: VulkanSharingMode) -> Self {
+        self.vulkan_sharing_modes.retain(|&other| other != mode);
+        self.vulkan_sharing_modes.insert(0, mode);
+        self
+    }
+
+    /// Only allows `mode`, device creation fails with
+    /// [`DeviceCreateError::OidnImportUnsupported`] if it is not supported by both wgpu and OIDN.
+    #[cfg(vulkan)]
+    pub fn require_vulkan_sharing_mode(mut self, mode: VulkanSharingMode) -> Self {
+        self.vulkan_sharing_modes = vec![mode];
+        self
+    }
+
+    /// Sets the OIDN `numThreads` device parameter (only used by CPU devices).
+    pub fn oidn_num_threads(mut self, num_threads: i32) -> Self {
+        self.oidn_options.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Sets the OIDN `setAffinity` device parameter (only used by CPU devices).
+    pub fn oidn_set_affinity(mut self, set_affinity: bool) -> Self {
+        self.oidn_options.set_affinity = Some(set_affinity);
+        self
+    }
+
+    /// Sets the OIDN `verbose` device parameter.
+    pub fn oidn_verbose(mut self, verbose: i32) -> Self {
+        self.oidn_options.verbose = Some(verbose);
+        self
+    }
+
+    /// Sets whether the device may copy buffers through the CPU when memory can't be shared,
+    /// see [`StagingMode`].
+    pub fn staging(mut self, staging: StagingMode) -> Self {
+        self.staging = staging;
+        self
+    }
+
+    /// Sets the type of OIDN device a staging device denoises on, [`OidnDeviceType::Cpu`] by
+    /// default.
+    ///
+    /// Staging copies through host memory, so any type works, but the default device may be a
+    /// GPU that the copies to and from host memory make slower than the CPU.
+    pub fn staging_oidn_device_type(mut self, device_type: OidnDeviceType) -> Self {
+        self.staging_oidn_device_type = device_type;
+        self
+    }
+
+    /// Creates a [`Device`] from an existing wgpu device, see [`Device::from_existing`].
+    ///
+    /// The device descriptor is ignored, as the device has already been created.
+    pub fn build_from_existing(
+        &self,
+        adapter: &wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+    ) -> Result<Device, DeviceCreateError> {
+        let result = match (self.staging, adapter.get_info().backend) {
+            #[cfg(gl)]
+            (_, wgpu::Backend::Gl) => return Device::from_existing_gl(self, device, queue),
+            (StagingMode::Always, _) => return Device::from_existing_staging(self, device, queue),
+            #[cfg(vulkan)]
+            (_, wgpu::Backend::Vulkan) => {
+                Device::from_existing_vulkan(adapter, self, device.clone(), queue.clone())
+            }
+            #[cfg(dx12)]
+            (_, wgpu::Backend::Dx12) => {
+                Device::from_existing_dx12(adapter, self, device.clone(), queue.clone())
+            }
+            (_, backend) => Err(DeviceCreateError::UnsupportedBackend(backend)),
+        };
+        match result {
+            Err(err) if self.staging == StagingMode::Fallback && err.allows_staging() => {
+                trace_event!(info, "Falling back to staging copies: {}", err);
+                Device::from_existing_staging(self, device, queue)
+            }
+            result => result,
+        }
+    }
+
+    pub async fn build(
+        &self,
+        adapter: &wgpu::Adapter,
+    ) -> Result<(Device, wgpu::Queue), DeviceCreateError> {
+        let result = match (self.staging, adapter.get_info().backend) {
+            #[cfg(gl)]
+            (_, wgpu::Backend::Gl) => return Device::new_gl(adapter, self).await,
+            (StagingMode::Always, _) => return Device::new_staging(adapter, self).await,
+            #[cfg(vulkan)]
+            (_, wgpu::Backend::Vulkan) => Device::new_vulkan(adapter, self).await,
+            #[cfg(dx12)]
+            (_, wgpu::Backend::Dx12) => Device::new_dx12(adapter, self).await,
+            (_, backend) => Err(DeviceCreateError::UnsupportedBackend(backend)),
+        };
+        match result {
+            Err(err) if self.staging == StagingMode::Fallback && err.allows_staging() => {
+                trace_event!(info, "Falling back to staging copies: {}", err);
+                Device::new_staging(adapter, self).await
+            }
+            result => result,
         }
     }
 }
 
+/// An OIDN device that can be shared between threads.
+///
+/// OIDN allows calling into a device from any thread and serialises the calls itself, and
+/// keeps the errors of each thread apart, so this needs no lock of its own. Objects created
+/// from the device, like filters, still must not be used from several threads at once.
+#[derive(Clone)]
+struct SharedOidnDevice(Arc<SyncOidnDevice>);
+
+struct SyncOidnDevice(oidn::Device);
+
+// # SAFETY: OIDN documents all API calls as thread safe, `oidn::Device` is only not `Sync`
+// because it holds the raw handle.
+unsafe impl Sync for SyncOidnDevice {}
+
+impl From<oidn::Device> for SharedOidnDevice {
+    fn from(device: oidn::Device) -> Self {
+        Self(Arc::new(SyncOidnDevice(device)))
+    }
+}
+
+impl Deref for SharedOidnDevice {
+    type Target = oidn::Device;
+
+    fn deref(&self) -> &oidn::Device {
+        &self.0.0
+    }
+}
+
+/// A wgpu device and the OIDN device sharing memory with it.
+///
+/// Cloning is cheap and the clones share both devices, so that for example buffers can be
+/// allocated on loader threads while another thread denoises.
+#[derive(Clone)]
 pub struct Device {
     wgpu_device: wgpu::Device,
-    oidn_device: oidn::Device,
+    oidn_device: SharedOidnDevice,
     queue: wgpu::Queue,
     backend_data: BackendData,
+    /// The format conversion pipelines, created when first needed.
+    converter: Arc<OnceLock<convert::Converter>>,
+    /// The thread running [`Denoiser::execute_async`], started when first needed.
+    worker: Arc<OnceLock<execute::Worker>>,
 }
 
 impl Device {
@@ -96,98 +618,203 @@ impl Device {
         adapter: &wgpu::Adapter,
         desc: &wgpu::DeviceDescriptor<'_>,
     ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
-        match adapter.get_info().backend {
-            #[cfg(vulkan)]
-            wgpu::Backend::Vulkan => Self::new_vulkan(adapter, desc).await,
-            #[cfg(dx12)]
-            wgpu::Backend::Dx12 => Self::new_dx12(adapter, desc).await,
-            _ => Err(DeviceCreateError::UnsupportedBackend(
-                adapter.get_info().backend,
-            )),
-        }
+        DeviceBuilder::new()
+            .device_descriptor(desc.clone())
+            .build(adapter)
+            .await
     }
     pub fn allocate_shared_buffers(
         &self,
-        size: wgpu::BufferAddress,
+        desc: &SharedBufferDescriptor<'_>,
     ) -> Result<SharedBuffer, SharedBufferCreateError> {
-        if size == 0 {
-            return Err(SharedBufferCreateError::InvalidSize(size));
+        if desc.size == 0 {
+            return Err(SharedBufferCreateError::InvalidSize(desc.size));
+        }
+        let map_usage = desc.usage & SharedBufferDescriptor::MAP_USAGES;
+        let unsupported = desc.usage - SharedBufferDescriptor::SUPPORTED_USAGES - map_usage;
+        if !unsupported.is_empty() {
+            return Err(SharedBufferCreateError::UnsupportedUsage(unsupported));
+        }
+        let memory_location = match desc.memory_location {
+            MemoryLocation::Auto if !map_usage.is_empty() || self.prefers_host_memory() => {
+                MemoryLocation::HostVisible
+            }
+            MemoryLocation::Auto => MemoryLocation::DeviceLocal,
+            location => location,
+        };
+        if !map_usage.is_empty()
+            && (memory_location == MemoryLocation::DeviceLocal || !self.supports_mapping())
+        {
+            return Err(SharedBufferCreateError::UnsupportedUsage(map_usage));
         }
+        let desc = SharedBufferDescriptor {
+            usage: desc.usage | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+            memory_location,
+            ..desc.clone()
+        };
+        match self.backend_data.as_backend() {
+            #[cfg(dx12)]
+            Backend::Dx12 => self.allocate_shared_buffers_dx12(&desc),
+            #[cfg(vulkan)]
+            Backend::Vulkan => self.allocate_shared_buffers_vulkan(&desc),
+            Backend::Staging => self.allocate_shared_buffers_staging(&desc),
+        }
+    }
+    /// Whether [`MemoryLocation::Auto`] should pick host visible memory.
+    fn prefers_host_memory(&self) -> bool {
+        OidnDeviceType::of(&self.oidn_device) == OidnDeviceType::Cpu
+            || self.wgpu_device.adapter_info().device_type == wgpu::DeviceType::IntegratedGpu
+    }
+
+    /// Whether wgpu can map host visible shared buffers.
+    ///
+    /// Shared buffers always have both copy usages, which wgpu only allows next to a map usage
+    /// with [`wgpu::Features::MAPPABLE_PRIMARY_BUFFERS`].
+    fn supports_mapping(&self) -> bool {
+        let mappable_primary_buffers = self
+            .wgpu_device
+            .features()
+            .contains(wgpu::Features::MAPPABLE_PRIMARY_BUFFERS);
         match self.backend_data.as_backend() {
             #[cfg(dx12)]
-            Backend::Dx12 => self.allocate_shared_buffers_dx12(size),
+            Backend::Dx12 => mappable_primary_buffers,
+            // wgpu-hal refuses to map memory it did not allocate itself
             #[cfg(vulkan)]
-            Backend::Vulkan => self.allocate_shared_buffers_vulkan(size),
+            Backend::Vulkan => false,
+            Backend::Staging => mappable_primary_buffers,
         }
     }
+
     pub fn oidn_device(&self) -> &oidn::Device {
         &self.oidn_device
     }
 
+    /// Another reference to `buffer`, which keeps it alive on its own.
+    pub(crate) fn retain_oidn_buffer(&self, buffer: &oidn::Buffer) -> oidn::Buffer {
+        unsafe {
+            oidn::sys::oidnRetainBuffer(buffer.raw());
+            self.oidn_device.create_buffer_from_raw(buffer.raw())
+        }
+    }
+
     pub fn wgpu_device(&self) -> &wgpu::Device {
         &self.wgpu_device
     }
 
-    async fn new_from_raw_oidn_adapter<
-        F: FnOnce(oidn::sys::OIDNExternalMemoryTypeFlag) -> Option<BackendData>,
+    /// Wraps a device that was already created from `adapter`, instead of requesting a new one.
+    ///
+    /// On Vulkan the device must have been created with the extensions of at least one
+    /// [`VulkanSharingMode`] that OIDN supports enabled, otherwise
+    /// [`DeviceCreateError::MissingDeviceExtensions`] is returned.
+    pub fn from_existing(
+        adapter: &wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+    ) -> Result<Self, DeviceCreateError> {
+        DeviceBuilder::new().build_from_existing(adapter, device, queue)
+    }
+
+    /// Commits `device` and picks how to share memory from the external memory types it supports.
+    fn oidn_device_from_raw<
+        T,
+        F: FnOnce(oidn::sys::OIDNExternalMemoryTypeFlag) -> Result<T, DeviceCreateError>,
     >(
         device: oidn::sys::OIDNDevice,
-        adapter: &wgpu::Adapter,
-        desc: &wgpu::DeviceDescriptor<'_>,
+        builder: &DeviceBuilder<'_>,
         backend_data_callback: F,
-    ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
+    ) -> Result<(oidn::Device, T), DeviceCreateError> {
         if device.is_null() {
             return Err(crate::DeviceCreateError::OidnUnsupported);
         }
 
         let supported_memory_types = unsafe {
+            builder.oidn_options.apply(device);
             oidn::sys::oidnCommitDevice(device);
             oidn::sys::oidnGetDeviceInt(device, b"externalMemoryTypes\0" as *const _ as _)
         } as oidn::sys::OIDNExternalMemoryTypeFlag;
-        let Some(backend_data) = backend_data_callback(supported_memory_types) else {
-            unsafe {
-                oidn::sys::oidnReleaseDevice(device);
-            }
-            return Err(DeviceCreateError::OidnImportUnsupported);
-        };
+        // dropping this releases the device if the callback fails
         let oidn_device = unsafe { oidn::Device::from_raw(device) };
-        let (wgpu_device, queue) = adapter
-            .request_device(desc)
-            .await
-            .map_err(crate::DeviceCreateError::RequestDeviceError)?;
-        Ok((
-            Self {
-                wgpu_device,
-                oidn_device,
-                queue: queue.clone(),
-                backend_data,
-            },
-            queue,
-        ))
+        let backend_data = backend_data_callback(supported_memory_types)?;
+        Ok((oidn_device, backend_data))
     }
 }
 
+/// Describes a [`SharedBuffer`].
+#[derive(Clone, Debug)]
+pub struct SharedBufferDescriptor<'a> {
+    /// Debug label of the buffer, also given to the Vulkan or D3D12 objects backing it.
+    pub label: wgpu::Label<'a>,
+    pub size: wgpu::BufferAddress,
+    /// Usages of the wgpu buffer. `COPY_SRC` and `COPY_DST` are always added.
+    pub usage: BufferUsages,
+    pub memory_location: MemoryLocation,
+}
+
+impl SharedBufferDescriptor<'_> {
+    /// The usages any shared buffer may have.
+    pub const SUPPORTED_USAGES: BufferUsages = BufferUsages::COPY_SRC
+        .union(BufferUsages::COPY_DST)
+        .union(BufferUsages::INDEX)
+        .union(BufferUsages::VERTEX)
+        .union(BufferUsages::UNIFORM)
+        .union(BufferUsages::STORAGE)
+        .union(BufferUsages::INDIRECT)
+        .union(BufferUsages::QUERY_RESOLVE);
+
+    /// The usages a shared buffer may additionally have if it is in host visible memory and the
+    /// backend can map it (D3D12 or a staging device, both with
+    /// [`wgpu::Features::MAPPABLE_PRIMARY_BUFFERS`] as shared buffers always have the copy
+    /// usages). wgpu can't map Vulkan memory it did not allocate itself.
+    pub const MAP_USAGES: BufferUsages = BufferUsages::MAP_READ.union(BufferUsages::MAP_WRITE);
+}
+
+/// Where the memory of a shared buffer lives.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum MemoryLocation {
+    /// Memory that is fastest for the GPU, but not accessible from the CPU.
+    #[default]
+    DeviceLocal,
+    /// Memory the CPU can access coherently, preferring memory that is also device local.
+    HostVisible,
+    /// Host visible memory if the buffer is mapped, OIDN runs on the CPU or the adapter is an
+    /// integrated GPU, otherwise device local memory.
+    Auto,
+}
+
 enum Allocation {
     // we keep these around to keep the allocations alive
     #[cfg(dx12)]
-    Dx12 { _dx12: dx12::Dx12Allocation },
+    Dx12 {
+        _dx12: dx12::Dx12Allocation,
+    },
     #[cfg(vulkan)]
     Vulkan,
+    Staging(staging::StagingAllocation),
 }
 
 pub struct SharedBuffer {
-    _allocation: Allocation,
+    allocation: Allocation,
     oidn_buffer: oidn::Buffer,
     wgpu_buffer: wgpu::Buffer,
+    owner: sync::Ownership,
+    /// The device the buffer was allocated from, to wait for wgpu before OIDN first uses it.
+    device: Device,
 }
 
 impl SharedBuffer {
+    /// The OIDN buffer, without checking that wgpu is done with it like
+    /// [`SharedBuffer::oidn_access`].
+    #[deprecated(note = "use `oidn_access`, which checks that wgpu is done with the buffer")]
     pub fn oidn_buffer(&self) -> &oidn::Buffer {
         &self.oidn_buffer
     }
+    #[deprecated(note = "use `oidn_access`, which checks that wgpu is done with the buffer")]
     pub fn oidn_buffer_mut(&mut self) -> &mut oidn::Buffer {
         &mut self.oidn_buffer
     }
+    /// The wgpu buffer, without checking that OIDN is done with it like
+    /// [`SharedBuffer::wgpu_access`].
+    #[deprecated(note = "use `wgpu_access`, which checks that OIDN is done with the buffer")]
     pub fn wgpu_buffer(&self) -> &wgpu::Buffer {
         &self.wgpu_buffer
     }
@@ -195,6 +822,7 @@ impl SharedBuffer {
 
 #[cfg(test)]
 #[async_std::test]
+#[allow(deprecated)]
 async fn test() {
     let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
         backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
@@ -221,7 +849,12 @@ async fn test() {
             }
         };
         let mut bufs = device
-            .allocate_shared_buffers(size_of::<[f32; 3]>() as wgpu::BufferAddress)
+            .allocate_shared_buffers(&SharedBufferDescriptor {
+                label: None,
+                size: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                usage: BufferUsages::empty(),
+                memory_location: MemoryLocation::DeviceLocal,
+            })
             .unwrap();
         queue.write_buffer(bufs.wgpu_buffer(), 0, &1.0_f32.to_ne_bytes());
         queue.submit([]);
@@ -232,9 +865,7 @@ async fn test() {
         assert_eq!(bufs.oidn_buffer_mut().read()[0], 1.0);
         let mut filter = oidn::RayTracing::new(device.oidn_device());
         filter.image_dimensions(1, 1);
-        filter
-            .filter_in_place_buffer(&mut bufs.oidn_buffer_mut())
-            .unwrap();
+        filter.filter_in_place_buffer(bufs.oidn_buffer()).unwrap();
         match device.oidn_device().get_error() {
             Ok(_) | Err((oidn::Error::OutOfMemory, _)) => {}
             Err(err) => panic!("{err:?}"),
@@ -242,10 +873,10 @@ async fn test() {
     }
 }
 
-// Ensure that dropping one or the other shared buffers does not break anything.
 #[cfg(test)]
 #[async_std::test]
-async fn test_validity() {
+#[allow(deprecated)]
+async fn test_from_existing() {
     let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
         backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
         ..wgpu::InstanceDescriptor::new_without_display_handle()
@@ -261,7 +892,58 @@ async fn test_validity() {
             }
             _ => continue,
         }
-        let (device, queue) = match Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        let Ok((wgpu_device, queue)) = adapter
+            .request_device(&wgpu::DeviceDescriptor::default())
+            .await
+        else {
+            continue;
+        };
+        let device = match Device::from_existing(&adapter, wgpu_device, queue.clone()) {
+            Ok(device) => device,
+            // Whether the extensions are enabled is up to wgpu.
+            Err(err @ DeviceCreateError::MissingDeviceExtensions(_)) => {
+                eprintln!("    {err:?}");
+                continue;
+            }
+            Err(err) => {
+                eprintln!("Device creation failed");
+                eprintln!("    {err:?}");
+                continue;
+            }
+        };
+        let mut bufs = device
+            .allocate_shared_buffers(&SharedBufferDescriptor {
+                label: None,
+                size: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                usage: BufferUsages::empty(),
+                memory_location: MemoryLocation::DeviceLocal,
+            })
+            .unwrap();
+        // the buffer was never handed over, so OIDN access waits for the submission
+        queue.write_buffer(bufs.wgpu_buffer(), 0, &1.0_f32.to_ne_bytes());
+        queue.submit([]);
+        assert_eq!(bufs.oidn_access().unwrap().read()[0], 1.0);
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn test_staging() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let (device, queue) = match DeviceBuilder::new()
+            .staging(StagingMode::Always)
+            .build(&adapter)
+            .await
         {
             Ok((device, queue)) => (device, queue),
             Err(err) => {
@@ -270,47 +952,1202 @@ async fn test_validity() {
                 continue;
             }
         };
-        {
-            let mut bufs = device
-                .allocate_shared_buffers(size_of::<[f32; 3]>() as wgpu::BufferAddress)
-                .unwrap();
-            queue.write_buffer(bufs.wgpu_buffer(), 0, &1.0_f32.to_ne_bytes());
-            queue.submit([]);
-            bufs.wgpu_buffer().destroy();
-            device
-                .wgpu_device()
-                .poll(wgpu::PollType::wait_indefinitely())
-                .unwrap();
-            assert_eq!(bufs.oidn_buffer_mut().read()[0], 1.0);
-            eprintln!("    Tested wgpu destroy");
-        }
-        {
-            use std::sync::mpsc;
-
-            use wgpu::{BufferAddress, BufferUsages, PollType, wgt::BufferDescriptor};
+        assert!(!device.is_zero_copy());
+        assert_eq!(
+            OidnDeviceType::of(device.oidn_device()),
+            OidnDeviceType::Cpu
+        );
+        let mut bufs = device
+            .allocate_shared_buffers(&SharedBufferDescriptor {
+                label: None,
+                size: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                usage: BufferUsages::empty(),
+                memory_location: MemoryLocation::DeviceLocal,
+            })
+            .unwrap();
+        bufs.wgpu_access()
+            .unwrap()
+            .write(&queue, 0, &1.0_f32.to_ne_bytes());
+        device.sync_to_oidn(&bufs).unwrap();
+        assert_eq!(bufs.oidn_access().unwrap().read()[0], 1.0);
+        bufs.oidn_access().unwrap().write(&[2.0, 3.0, 4.0]);
+        device.sync_to_wgpu(&bufs).unwrap();
+        queue.submit([]);
+        device.sync_to_oidn(&bufs).unwrap();
+        assert_eq!(bufs.oidn_access().unwrap().read(), [2.0, 3.0, 4.0]);
+    }
+}
 
-            let bufs = device
-                .allocate_shared_buffers(size_of::<[f32; 3]>() as wgpu::BufferAddress)
-                .unwrap();
-            let buffer = bufs.wgpu_buffer().clone();
-            drop(bufs);
-            queue.write_buffer(&buffer, 0, &1.0_f32.to_ne_bytes());
-            let readback_buffer = device.wgpu_device().create_buffer(&BufferDescriptor {
-                label: Some("readback"),
-                size: size_of::<f32>() as _,
-                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-                mapped_at_creation: false,
+#[cfg(test)]
+#[async_std::test]
+async fn test_release_acquire() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let Ok((device, queue)) = DeviceBuilder::new()
+            .staging(StagingMode::Fallback)
+            .build(&adapter)
+            .await
+        else {
+            continue;
+        };
+        let mut bufs = device
+            .allocate_shared_buffers(&SharedBufferDescriptor {
+                label: None,
+                size: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                usage: BufferUsages::COPY_DST,
+                memory_location: MemoryLocation::Auto,
+            })
+            .unwrap();
+        bufs.wgpu_access()
+            .unwrap()
+            .write(&queue, 0, &1.0_f32.to_ne_bytes());
+        let submission = queue.submit([]);
+        device
+            .acquire_for_oidn(bufs.release_to_oidn(submission))
+            .unwrap();
+        assert_eq!(bufs.oidn_access().unwrap().read()[0], 1.0);
+        device.acquire_for_wgpu(bufs.release_to_wgpu()).unwrap();
+        assert!(matches!(bufs.oidn_access(), Err(AccessError::OwnedByWgpu)));
+        bufs.wgpu_access()
+            .unwrap()
+            .write(&queue, 0, &2.0_f32.to_ne_bytes());
+        let submission = queue.submit([]);
+        device
+            .acquire_for_oidn(bufs.release_to_oidn(submission))
+            .unwrap();
+        assert_eq!(bufs.oidn_access().unwrap().read()[0], 2.0);
+        assert!(matches!(bufs.wgpu_access(), Err(AccessError::OwnedByOidn)));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync + Clone>() {}
+    fn assert_send<T: Send>() {}
+    assert_send_sync::<Device>();
+    assert_send::<SharedBuffer>();
+    assert_send::<Denoiser<'static>>();
+}
+
+// Clones of a device allocating and using shared buffers on several threads at once.
+#[cfg(test)]
+#[async_std::test]
+async fn test_concurrent_allocation() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let Ok((device, queue)) = DeviceBuilder::new()
+            .staging(StagingMode::Fallback)
+            .build(&adapter)
+            .await
+        else {
+            continue;
+        };
+        let mut buffers = std::thread::scope(|scope| {
+            let threads = (0..4)
+                .map(|thread| {
+                    let device = device.clone();
+                    let queue = &queue;
+                    scope.spawn(move || {
+                        (0..8)
+                            .map(|index| {
+                                let value = (thread * 8 + index) as f32;
+                                let mut bufs = device
+                                    .allocate_shared_buffers(&SharedBufferDescriptor {
+                                        label: None,
+                                        size: (index + 1) * size_of::<f32>() as u64,
+                                        usage: BufferUsages::COPY_DST,
+                                        memory_location: MemoryLocation::Auto,
+                                    })
+                                    .unwrap();
+                                bufs.wgpu_access()
+                                    .unwrap()
+                                    .write(queue, 0, &value.to_ne_bytes());
+                                let submission = queue.submit([]);
+                                device
+                                    .acquire_for_oidn(bufs.release_to_oidn(submission))
+                                    .unwrap();
+                                assert_eq!(bufs.oidn_access().unwrap().read()[0], value);
+                                (value, bufs)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        // the buffers stay usable on this thread, through the original device
+        assert_eq!(buffers.len(), 32);
+        for (value, bufs) in &mut buffers {
+            device.sync_to_oidn(bufs).unwrap();
+            assert_eq!(bufs.oidn_access().unwrap().read()[0], *value);
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+#[allow(deprecated)]
+async fn test_usages() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let desc = wgpu::DeviceDescriptor {
+            required_features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
+            ..Default::default()
+        };
+        let Ok((device, queue)) = Device::new(&adapter, &desc).await else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let size = size_of::<[f32; 4]>() as wgpu::BufferAddress;
+        let mut bufs = device
+            .allocate_shared_buffers(&SharedBufferDescriptor {
+                label: Some("test_usages"),
+                size,
+                usage: SharedBufferDescriptor::SUPPORTED_USAGES,
+                memory_location: MemoryLocation::DeviceLocal,
+            })
+            .unwrap();
+        let wgpu_buffer = bufs.wgpu_access().unwrap();
+        assert_eq!(
+            wgpu_buffer.usage(),
+            SharedBufferDescriptor::SUPPORTED_USAGES
+        );
+        let layout =
+            device
+                .wgpu_device()
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: None,
+                    entries: &[wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    }],
+                });
+        let _bind_group = device
+            .wgpu_device()
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu_buffer.as_entire_binding(),
+                }],
+            });
+        assert!(matches!(
+            device.allocate_shared_buffers(&SharedBufferDescriptor {
+                label: None,
+                size,
+                usage: BufferUsages::MAP_READ | BufferUsages::STORAGE,
+                memory_location: MemoryLocation::DeviceLocal,
+            }),
+            Err(SharedBufferCreateError::UnsupportedUsage(
+                BufferUsages::MAP_READ
+            ))
+        ));
+        let host_visible = device.allocate_shared_buffers(&SharedBufferDescriptor {
+            label: None,
+            size,
+            usage: BufferUsages::MAP_READ,
+            memory_location: MemoryLocation::HostVisible,
+        });
+        match host_visible {
+            Ok(_) => assert!(device.supports_mapping()),
+            Err(SharedBufferCreateError::UnsupportedUsage(_)) => {
+                assert!(!device.supports_mapping())
+            }
+            Err(err) => eprintln!("    {err:?}"),
+        }
+
+        // wgpu binds indirect and query resolve buffers as storage buffers internally
+        let errors = device
+            .wgpu_device()
+            .push_error_scope(wgpu::ErrorFilter::Validation);
+        let mut indirect = device
+            .allocate_shared_buffers(&SharedBufferDescriptor {
+                label: Some("test_usages indirect"),
+                size: size_of::<[u32; 3]>() as wgpu::BufferAddress,
+                usage: BufferUsages::INDIRECT,
+                memory_location: MemoryLocation::DeviceLocal,
+            })
+            .unwrap();
+        indirect.wgpu_access().unwrap().write(
+            &queue,
+            0,
+            &[1_u32; 3].map(u32::to_ne_bytes).concat(),
+        );
+        let mut resolve = device
+            .allocate_shared_buffers(&SharedBufferDescriptor {
+                label: Some("test_usages resolve"),
+                size: size_of::<[u64; 2]>() as wgpu::BufferAddress,
+                usage: BufferUsages::QUERY_RESOLVE,
+                memory_location: MemoryLocation::DeviceLocal,
+            })
+            .unwrap();
+        let query_set = device
+            .wgpu_device()
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+            .then(|| {
+                device
+                    .wgpu_device()
+                    .create_query_set(&wgpu::QuerySetDescriptor {
+                        label: None,
+                        ty: wgpu::QueryType::Timestamp,
+                        count: 2,
+                    })
+            });
+        let module = device
+            .wgpu_device()
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl("@compute @workgroup_size(1) fn main() {}".into()),
+            });
+        let pipeline =
+            device
+                .wgpu_device()
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: None,
+                    layout: None,
+                    module: &module,
+                    entry_point: None,
+                    compilation_options: Default::default(),
+                    cache: None,
+                });
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: query_set.as_ref().map(|query_set| {
+                    wgpu::ComputePassTimestampWrites {
+                        query_set,
+                        beginning_of_pass_write_index: Some(0),
+                        end_of_pass_write_index: Some(1),
+                    }
+                }),
+            });
+            pass.set_pipeline(&pipeline);
+            // passes and query resolves take the `wgpu::Buffer` itself, which the access guards
+            // don't hand out
+            pass.dispatch_workgroups_indirect(indirect.wgpu_buffer(), 0);
+        }
+        if let Some(query_set) = &query_set {
+            encoder.resolve_query_set(query_set, 0..2, resolve.wgpu_buffer(), 0);
+        }
+        queue.submit([encoder.finish()]);
+        device.sync_to_oidn(&resolve).unwrap();
+        assert!(errors.pop().await.is_none());
+        if query_set.is_some() {
+            let bytes = resolve
+                .oidn_access()
+                .unwrap()
+                .read()
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect::<Vec<_>>();
+            let timestamp =
+                |index: usize| u64::from_ne_bytes(bytes[index * 8..][..8].try_into().unwrap());
+            assert!(timestamp(1) >= timestamp(0));
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn test_shared_texture() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, queue)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let desc = SharedTextureDescriptor {
+            label: Some("test_shared_texture"),
+            width: 3,
+            height: 2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::COPY_DST,
+        };
+        let mut texture = match device.allocate_shared_texture(&desc) {
+            Ok(texture) => texture,
+            Err(err) => {
+                eprintln!("    {err:?}");
+                continue;
+            }
+        };
+        let layout = texture.layout();
+        assert_eq!(layout.format, oidn::sys::OIDNFormat_OIDN_FORMAT_FLOAT3);
+        assert_eq!(layout.pixel_byte_stride, size_of::<[f32; 4]>());
+        assert!(layout.row_byte_stride >= layout.pixel_byte_stride * 3);
+        let texels: Vec<f32> = (0..3 * 2 * 4).map(|i| i as f32).collect();
+        let bytes: Vec<u8> = texels
+            .iter()
+            .flat_map(|texel| texel.to_ne_bytes())
+            .collect();
+        queue.write_texture(
+            texture.wgpu_access().unwrap().as_image_copy(),
+            &bytes,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(3 * 16),
+                rows_per_image: None,
+            },
+            desc.size(),
+        );
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        texture.encode_oidn_layout(&mut encoder);
+        queue.submit([encoder.finish()]);
+        assert!(matches!(
+            texture.oidn_access(),
+            Err(AccessError::OwnedByWgpu)
+        ));
+        device.sync_texture_to_oidn(&texture).unwrap();
+        let contents = texture.oidn_access().unwrap().read();
+        for y in 0..2 {
+            for x in 0..3 {
+                let start = (layout.byte_offset
+                    + y * layout.row_byte_stride
+                    + x * layout.pixel_byte_stride)
+                    / size_of::<f32>();
+                assert_eq!(contents[start..][..4], texels[(y * 3 + x) * 4..][..4]);
+            }
+        }
+        assert!(matches!(
+            device.allocate_shared_texture(&SharedTextureDescriptor {
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                ..desc
+            }),
+            Err(SharedBufferCreateError::UnsupportedFormat(
+                wgpu::TextureFormat::Rgba8Unorm
+            ))
+        ));
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn test_conversion() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, queue)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let (width, height) = (3, 2);
+        let texture = device
+            .wgpu_device()
+            .create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::COPY_DST
+                    | wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::STORAGE_BINDING,
+                view_formats: &[],
             });
+        let texels: Vec<f32> = (0..width * height * 4).map(|i| i as f32).collect();
+        let bytes: Vec<u8> = texels
+            .iter()
+            .flat_map(|texel| texel.to_ne_bytes())
+            .collect();
+        queue.write_texture(
+            texture.as_image_copy(),
+            &bytes,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(width * 16),
+                rows_per_image: None,
+            },
+            texture.size(),
+        );
+        let desc = SharedBufferDescriptor {
+            label: Some("test_conversion"),
+            size: BufferFormat::Float3.buffer_size(width, height),
+            usage: BufferUsages::STORAGE,
+            memory_location: MemoryLocation::DeviceLocal,
+        };
+        let mut first = device.allocate_shared_buffers(&desc).unwrap();
+        let mut second = device.allocate_shared_buffers(&desc).unwrap();
+        let exposure = ConversionOptions {
+            exposure: 2.0,
+            ..Default::default()
+        };
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        {
+            let first = first.wgpu_access().unwrap();
+            device
+                .encode_texture_to_buffer(&mut encoder, &texture, first.slice(..), &exposure)
+                .unwrap();
+            // converting back undoes the exposure
+            device
+                .encode_buffer_to_texture(&mut encoder, first.slice(..), &texture, &exposure)
+                .unwrap();
+            device
+                .encode_texture_to_buffer(
+                    &mut encoder,
+                    &texture,
+                    second.wgpu_access().unwrap().slice(..),
+                    &Default::default(),
+                )
+                .unwrap();
+        }
+        queue.submit([encoder.finish()]);
+        device.sync_to_oidn(&first).unwrap();
+        device.sync_to_oidn(&second).unwrap();
+        let expected = |scale: f32| -> Vec<f32> {
+            texels
+                .chunks(4)
+                .flat_map(|texel| texel[..3].iter().map(move |channel| channel * scale))
+                .collect()
+        };
+        assert_eq!(first.oidn_access().unwrap().read(), expected(2.0));
+        assert_eq!(second.oidn_access().unwrap().read(), expected(1.0));
+        device.sync_to_wgpu(&first).unwrap();
+        let first = first.wgpu_access().unwrap();
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        assert!(matches!(
+            device.encode_texture_to_buffer(
+                &mut encoder,
+                &texture,
+                first.slice(..12),
+                &Default::default(),
+            ),
+            Err(ConversionError::BufferTooSmall {
+                required: 72,
+                actual: 12
+            })
+        ));
+        assert!(matches!(
+            device.encode_texture_to_buffer(
+                &mut encoder,
+                &texture,
+                first.slice(4..),
+                &Default::default(),
+            ),
+            Err(ConversionError::UnalignedOffset { offset: 4, .. })
+        ));
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+#[allow(deprecated)]
+async fn test_denoiser() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, queue)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let (width, height) = (16, 16);
+        let create_texture = |width| {
+            device
+                .wgpu_device()
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: None,
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING
+                        | wgpu::TextureUsages::STORAGE_BINDING,
+                    view_formats: &[],
+                })
+        };
+        let color = create_texture(width);
+        let albedo = create_texture(width);
+        let output = create_texture(width);
+        let mut denoiser = device
+            .create_denoiser(&DenoiserDescriptor {
+                width,
+                height,
+                buffer_format: BufferFormat::Float3,
+                albedo: true,
+                normal: false,
+                settings: DenoiserSettings::default(),
+                tile_size: None,
+            })
+            .unwrap();
+        for aux_mode in [AuxMode::Prefilter, AuxMode::Noisy, AuxMode::Clean] {
+            denoiser
+                .set_settings(DenoiserSettings {
+                    aux_mode,
+                    ..Default::default()
+                })
+                .unwrap();
             let mut encoder = device
                 .wgpu_device()
                 .create_command_encoder(&Default::default());
-            encoder.copy_buffer_to_buffer(
-                &buffer,
-                0,
-                &readback_buffer,
-                0,
-                size_of::<f32>() as BufferAddress,
+            denoiser
+                .encode_inputs(
+                    &mut encoder,
+                    &DenoiseInputs {
+                        color: &color,
+                        albedo: Some(&albedo),
+                        normal: None,
+                    },
+                )
+                .unwrap();
+            denoiser.execute(queue.submit([encoder.finish()])).unwrap();
+            let mut encoder = device
+                .wgpu_device()
+                .create_command_encoder(&Default::default());
+            denoiser.encode_output(&mut encoder, &output).unwrap();
+            queue.submit([encoder.finish()]);
+            device.sync_to_oidn(denoiser.output_buffer()).unwrap();
+            // the textures are zeroed, so is the denoised image
+            assert!(
+                denoiser
+                    .output_buffer()
+                    .oidn_buffer()
+                    .read()
+                    .iter()
+                    .all(|channel| *channel == 0.0)
             );
+        }
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        assert!(matches!(
+            denoiser.encode_inputs(
+                &mut encoder,
+                &DenoiseInputs {
+                    color: &color,
+                    albedo: None,
+                    normal: None,
+                },
+            ),
+            Err(DenoiseError::MissingInput("albedo"))
+        ));
+        assert!(matches!(
+            denoiser.encode_inputs(
+                &mut encoder,
+                &DenoiseInputs {
+                    color: &create_texture(width * 2),
+                    albedo: Some(&albedo),
+                    normal: None,
+                },
+            ),
+            Err(DenoiseError::SizeMismatch { .. })
+        ));
+        assert!(matches!(
+            denoiser.encode_output(&mut encoder, &create_texture(width * 2)),
+            Err(DenoiseError::SizeMismatch { .. })
+        ));
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn test_denoise_async() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, queue)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let (width, height) = (33, 17);
+        let texture = device
+            .wgpu_device()
+            .create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba16Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+                view_formats: &[],
+            });
+        let inputs = DenoiseInputs {
+            color: &texture,
+            albedo: None,
+            normal: None,
+        };
+        let desc = DenoiserDescriptor {
+            width,
+            height,
+            buffer_format: BufferFormat::Half3,
+            albedo: false,
+            normal: false,
+            settings: DenoiserSettings::default(),
+            tile_size: None,
+        };
+        let encode_inputs = |denoiser: &Denoiser| {
+            let mut encoder = device
+                .wgpu_device()
+                .create_command_encoder(&Default::default());
+            denoiser.encode_inputs(&mut encoder, &inputs).unwrap();
+            queue.submit([encoder.finish()])
+        };
+        let denoiser = device.create_denoiser(&desc).unwrap();
+        let execution = denoiser.execute_async(encode_inputs(&denoiser)).unwrap();
+        execution.await.unwrap();
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        denoiser.encode_output(&mut encoder, &texture).unwrap();
+        queue.submit([encoder.finish()]);
+        // a second run without awaiting waits for the first one
+        let first = denoiser.execute_async(encode_inputs(&denoiser)).unwrap();
+        let second = denoiser.execute_async(encode_inputs(&denoiser)).unwrap();
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        denoiser.encode_output(&mut encoder, &texture).unwrap();
+        queue.submit([encoder.finish()]);
+        first.await.unwrap();
+        second.await.unwrap();
+
+        // tiles are converted one after the other, which only the blocking `denoise` does
+        let tiled = device
+            .create_denoiser(&DenoiserDescriptor {
+                tile_size: Some(16),
+                ..desc
+            })
+            .unwrap();
+        assert!(matches!(
+            tiled.execute_async(queue.submit([])),
+            Err(DenoiseError::Tiled)
+        ));
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+#[allow(deprecated)]
+async fn test_tiled_denoiser() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, queue)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        // rows of 1200 float texels are aligned for copies
+        let (width, height) = (1200, 40);
+        let create_texture = || {
+            device
+                .wgpu_device()
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: None,
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING
+                        | wgpu::TextureUsages::STORAGE_BINDING
+                        | wgpu::TextureUsages::COPY_SRC
+                        | wgpu::TextureUsages::COPY_DST,
+                    view_formats: &[],
+                })
+        };
+        let color = create_texture();
+        let output = create_texture();
+        let row_size = width * size_of::<[f32; 4]>() as u32;
+        let ones: Vec<u8> = (0..width * height * 4)
+            .flat_map(|_| 1.0_f32.to_ne_bytes())
+            .collect();
+        queue.write_texture(
+            output.as_image_copy(),
+            &ones,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(row_size),
+                rows_per_image: None,
+            },
+            output.size(),
+        );
+        let denoiser = device
+            .create_denoiser(&DenoiserDescriptor {
+                width,
+                height,
+                buffer_format: BufferFormat::Half3,
+                albedo: false,
+                normal: false,
+                settings: DenoiserSettings::default(),
+                tile_size: Some(512),
+            })
+            .unwrap();
+        // the shared buffers only hold a tile
+        assert_eq!(
+            denoiser.color_buffer().wgpu_buffer().size(),
+            BufferFormat::Half3.buffer_size(512, height)
+        );
+        let inputs = DenoiseInputs {
+            color: &color,
+            albedo: None,
+            normal: None,
+        };
+        // the tiles next to the written ones still read the input
+        assert!(matches!(
+            denoiser.denoise(&inputs, &color),
+            Err(DenoiseError::OutputIsInput)
+        ));
+        denoiser.denoise(&inputs, &output).unwrap();
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        let readback = device.wgpu_device().create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: wgpu::BufferAddress::from(row_size * height),
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            output.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &readback,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(row_size),
+                    rows_per_image: None,
+                },
+            },
+            output.size(),
+        );
+        encoder.map_buffer_on_submit(&readback, wgpu::MapMode::Read, .., |res| res.unwrap());
+        queue.submit([encoder.finish()]);
+        device
+            .wgpu_device()
+            .poll(wgpu::PollType::wait_indefinitely())
+            .unwrap();
+        // every tile wrote its part of the zeroed image into the output, the alpha is set to one
+        let texels = readback.get_mapped_range(..);
+        for texel in texels.chunks_exact(size_of::<[f32; 4]>()) {
+            let channel =
+                |i: usize| f32::from_ne_bytes(texel[i * 4..i * 4 + 4].try_into().unwrap());
+            assert_eq!(
+                [channel(0), channel(1), channel(2), channel(3)],
+                [0.0, 0.0, 0.0, 1.0]
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn test_lightmap_denoiser() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, queue)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let (width, height) = (17, 9);
+        let lightmap = device
+            .wgpu_device()
+            .create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba16Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+                view_formats: &[],
+            });
+        let mut denoiser = device
+            .create_lightmap_denoiser(&LightmapDenoiserDescriptor {
+                width,
+                height,
+                buffer_format: BufferFormat::Half3,
+                settings: LightmapSettings::default(),
+                tile_size: None,
+            })
+            .unwrap();
+        denoiser
+            .set_settings(LightmapSettings {
+                directional: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(denoiser.settings().directional);
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        denoiser.encode_input(&mut encoder, &lightmap).unwrap();
+        denoiser.execute(queue.submit([encoder.finish()])).unwrap();
+        let mut encoder = device
+            .wgpu_device()
+            .create_command_encoder(&Default::default());
+        denoiser.encode_output(&mut encoder, &lightmap).unwrap();
+        queue.submit([encoder.finish()]);
+        device
+            .wgpu_device()
+            .poll(wgpu::PollType::wait_indefinitely())
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn test_pool() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, queue)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let pool = device
+            .create_shared_buffer_pool(&SharedBufferDescriptor {
+                label: Some("test_pool"),
+                size: 4096,
+                usage: BufferUsages::STORAGE,
+                memory_location: MemoryLocation::DeviceLocal,
+            })
+            .unwrap();
+        let size = size_of::<[f32; 3]>() as wgpu::BufferAddress;
+        let first = pool.allocate(size).unwrap();
+        let second = pool.allocate(size).unwrap();
+        assert_eq!(second.offset() % pool.alignment(), 0);
+        assert_ne!(first.offset(), second.offset());
+        let wgpu_buffer = first.wgpu_slice().unwrap().buffer();
+        queue.write_buffer(wgpu_buffer, first.offset(), &1.0_f32.to_ne_bytes());
+        queue.write_buffer(wgpu_buffer, second.offset(), &2.0_f32.to_ne_bytes());
+        let submission = queue.submit([]);
+        assert!(matches!(first.read(), Err(AccessError::OwnedByWgpu)));
+        device
+            .acquire_for_oidn(pool.buffer().release_to_oidn(submission))
+            .unwrap();
+        assert_eq!(first.read().unwrap()[0], 1.0);
+        assert_eq!(second.read().unwrap()[0], 2.0);
+        second.write(&[3.0]).unwrap();
+        assert_eq!(second.read().unwrap()[0], 3.0);
+        assert_eq!(first.read().unwrap()[0], 1.0);
+        assert!(matches!(second.wgpu_slice(), Err(AccessError::OwnedByOidn)));
+        drop(first);
+        assert!(matches!(
+            pool.allocate(4096),
+            Err(SharedBufferCreateError::PoolExhausted(4096))
+        ));
+    }
+}
+
+// `async_std::test` can't parse C string literals.
+#[cfg(test)]
+fn denoise_pool_slices(
+    device: &Device,
+    color: &SharedBufferSlice<'_>,
+    output: &SharedBufferSlice<'_>,
+    width: u32,
+    height: u32,
+) -> Vec<f32> {
+    let filter = denoise::RawFilter::new(device.oidn_device(), c"RT").unwrap();
+    unsafe {
+        color
+            .set_filter_image(filter.raw(), c"color", BufferFormat::Float3, width, height)
+            .unwrap();
+        output
+            .set_filter_image(filter.raw(), c"output", BufferFormat::Float3, width, height)
+            .unwrap();
+    }
+    filter.commit();
+    filter.execute();
+    device.oidn_device().get_error().unwrap();
+    output.read().unwrap()
+}
+
+// Pool slices are bound at their offset into the pool's buffer, as OIDN can't view part of an
+// imported buffer on GPU devices.
+#[cfg(test)]
+#[async_std::test]
+async fn test_pool_filter() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let Ok((device, _)) = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        else {
+            continue;
+        };
+        if OidnDeviceType::of(device.oidn_device()) == OidnDeviceType::Cpu {
+            continue;
+        }
+        eprintln!(
+            "Testing {} device {}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let (width, height) = (16, 16);
+        let size = BufferFormat::Float3.buffer_size(width, height);
+        let pool = device
+            .create_shared_buffer_pool(&SharedBufferDescriptor {
+                label: Some("test_pool_filter"),
+                size: size * 4,
+                usage: BufferUsages::STORAGE,
+                memory_location: MemoryLocation::DeviceLocal,
+            })
+            .unwrap();
+        // not at the start of the pool
+        let _padding = pool.allocate(size).unwrap();
+        let color = pool.allocate(size).unwrap();
+        let output = pool.allocate(size).unwrap();
+        color
+            .write(&vec![0.5; (width * height * 3) as usize])
+            .unwrap();
+        let denoised = denoise_pool_slices(&device, &color, &output, width, height);
+        assert!(denoised.iter().all(|channel| (channel - 0.5).abs() < 0.1));
+    }
+}
+
+// Probing should agree with actually creating the device.
+#[cfg(test)]
+#[async_std::test]
+async fn test_probe() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        let capabilities = probe(&adapter);
+        eprintln!(
+            "Probed {} device {}: {capabilities:?}",
+            adapter.get_info().backend,
+            adapter.get_info().name
+        );
+        let result = Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await;
+        assert_eq!(capabilities.is_supported(), result.is_ok());
+        #[cfg(vulkan)]
+        if let Ok((device, _)) = result
+            && let BackendData::Vulkan(mode) = device.backend_data
+        {
+            assert_eq!(capabilities.vulkan_sharing_mode, Some(mode));
+        }
+    }
+}
+
+#[cfg(all(test, vulkan))]
+#[test]
+fn test_vulkan_sharing_mode_order() {
+    let builder = DeviceBuilder::new().prefer_vulkan_sharing_mode(VulkanSharingMode::Dma);
+    assert_eq!(
+        builder.vulkan_sharing_modes,
+        [
+            VulkanSharingMode::Dma,
+            VulkanSharingMode::Win32,
+            VulkanSharingMode::Fd
+        ]
+    );
+    let builder = builder.require_vulkan_sharing_mode(VulkanSharingMode::Fd);
+    assert_eq!(builder.vulkan_sharing_modes, [VulkanSharingMode::Fd]);
+}
+
+// Ensure that dropping one or the other shared buffers does not break anything.
+#[cfg(test)]
+#[async_std::test]
+#[allow(deprecated)]
+async fn test_validity() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        ..wgpu::InstanceDescriptor::new_without_display_handle()
+    });
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    for adapter in adapters {
+        match adapter.get_info().backend {
+            wgpu::Backend::Vulkan => {
+                eprintln!("Testing vulkan device {}", adapter.get_info().name);
+            }
+            wgpu::Backend::Dx12 => {
+                eprintln!("Testing dx12 device {}", adapter.get_info().name);
+            }
+            _ => continue,
+        }
+        let (device, queue) = match Device::new(&adapter, &wgpu::DeviceDescriptor::default()).await
+        {
+            Ok((device, queue)) => (device, queue),
+            Err(err) => {
+                eprintln!("Device creation failed");
+                eprintln!("    {err:?}");
+                continue;
+            }
+        };
+        {
+            let mut bufs = device
+                .allocate_shared_buffers(&SharedBufferDescriptor {
+                    label: None,
+                    size: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    usage: BufferUsages::empty(),
+                    memory_location: MemoryLocation::DeviceLocal,
+                })
+                .unwrap();
+            queue.write_buffer(bufs.wgpu_buffer(), 0, &1.0_f32.to_ne_bytes());
+            queue.submit([]);
+            bufs.wgpu_buffer().destroy();
+            device
+                .wgpu_device()
+                .poll(wgpu::PollType::wait_indefinitely())
+                .unwrap();
+            assert_eq!(bufs.oidn_buffer_mut().read()[0], 1.0);
+            eprintln!("    Tested wgpu destroy");
+        }
+        {
+            use std::sync::mpsc;
+
+            use wgpu::{BufferAddress, BufferUsages, PollType, wgt::BufferDescriptor};
+
+            let mut bufs = device
+                .allocate_shared_buffers(&SharedBufferDescriptor {
+                    label: None,
+                    size: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    usage: BufferUsages::empty(),
+                    memory_location: MemoryLocation::DeviceLocal,
+                })
+                .unwrap();
+            let readback_buffer = device.wgpu_device().create_buffer(&BufferDescriptor {
+                label: Some("readback"),
+                size: size_of::<f32>() as _,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let mut encoder = device
+                .wgpu_device()
+                .create_command_encoder(&Default::default());
+            {
+                let buffer = bufs.wgpu_access().unwrap();
+                buffer.write(&queue, 0, &1.0_f32.to_ne_bytes());
+                buffer.encode_copy_to_buffer(
+                    &mut encoder,
+                    0,
+                    &readback_buffer,
+                    0,
+                    size_of::<f32>() as BufferAddress,
+                );
+            }
+            // the queued write and the recorded copy keep the wgpu buffer alive
+            drop(bufs);
             let (send, recv) = mpsc::channel();
             encoder.map_buffer_on_submit(&readback_buffer, wgpu::MapMode::Read, .., move |_| {
                 send.send(()).unwrap()
@@ -0,0 +1,270 @@
+use std::ffi::CStr;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use wgpu::BufferAddress;
+use wgpu::util::align_to;
+
+use crate::{
+    AccessError, BufferFormat, Device, SharedBuffer, SharedBufferCreateError,
+    SharedBufferDescriptor,
+};
+
+/// Byte alignment OIDN needs for the start of an image.
+const OIDN_ALIGNMENT: BufferAddress = 16;
+
+/// First fit allocator over the ranges of a pool that are not handed out.
+struct FreeList {
+    /// Sorted and never adjacent, adjacent ranges are merged on free.
+    free: Vec<Range<BufferAddress>>,
+}
+
+impl FreeList {
+    fn new(size: BufferAddress) -> Self {
+        Self {
+            free: vec![Range {
+                start: 0,
+                end: size,
+            }],
+        }
+    }
+
+    fn allocate(&mut self, size: BufferAddress, alignment: BufferAddress) -> Option<BufferAddress> {
+        let (index, offset) = self.free.iter().enumerate().find_map(|(index, range)| {
+            let offset = align_to(range.start, alignment);
+            (offset + size <= range.end).then_some((index, offset))
+        })?;
+        let range = self.free.remove(index);
+        // keep the padding before and the rest after the allocation
+        let mut insert = index;
+        if range.start < offset {
+            self.free.insert(insert, range.start..offset);
+            insert += 1;
+        }
+        if offset + size < range.end {
+            self.free.insert(insert, offset + size..range.end);
+        }
+        Some(offset)
+    }
+
+    fn free(&mut self, range: Range<BufferAddress>) {
+        let index = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(index, range);
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            let next = self.free.remove(index + 1);
+            self.free[index].end = next.end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            let current = self.free.remove(index);
+            self.free[index - 1].end = current.end;
+        }
+    }
+}
+
+/// One shared allocation that hands out sub-ranges as [`SharedBufferSlice`]s.
+///
+/// Every [`SharedBuffer`] needs its own exported allocation, while a pool only needs one no
+/// matter how many slices it hands out.
+pub struct SharedBufferPool {
+    buffer: SharedBuffer,
+    alignment: BufferAddress,
+    free_list: Mutex<FreeList>,
+}
+
+impl Device {
+    /// Creates a pool of `desc.size` bytes, the usages of `desc` apply to every slice.
+    pub fn create_shared_buffer_pool(
+        &self,
+        desc: &SharedBufferDescriptor<'_>,
+    ) -> Result<SharedBufferPool, SharedBufferCreateError> {
+        let buffer = self.allocate_shared_buffers(desc)?;
+        // Slices must be usable as bindings as well as OIDN images.
+        let limits = self.wgpu_device.limits();
+        let alignment = BufferAddress::from(limits.min_storage_buffer_offset_alignment)
+            .max(BufferAddress::from(
+                limits.min_uniform_buffer_offset_alignment,
+            ))
+            .max(OIDN_ALIGNMENT);
+        Ok(SharedBufferPool {
+            buffer,
+            alignment,
+            free_list: Mutex::new(FreeList::new(desc.size)),
+        })
+    }
+}
+
+impl SharedBufferPool {
+    /// Hands out `size` bytes of the pool.
+    ///
+    /// Fails with [`SharedBufferCreateError::PoolExhausted`] if no free range is large enough.
+    pub fn allocate(
+        &self,
+        size: BufferAddress,
+    ) -> Result<SharedBufferSlice<'_>, SharedBufferCreateError> {
+        if size == 0 {
+            return Err(SharedBufferCreateError::InvalidSize(size));
+        }
+        let offset = self
+            .free_list
+            .lock()
+            .unwrap()
+            .allocate(size, self.alignment)
+            .ok_or(SharedBufferCreateError::PoolExhausted(size))?;
+        Ok(SharedBufferSlice {
+            pool: self,
+            offset,
+            size,
+        })
+    }
+
+    /// The buffer backing every slice.
+    ///
+    /// Handing it to OIDN or wgpu, with [`Device::sync_to_oidn`] and [`Device::sync_to_wgpu`] or
+    /// the release and acquire functions, hands over every slice.
+    pub fn buffer(&self) -> &SharedBuffer {
+        &self.buffer
+    }
+
+    /// The alignment of every slice offset.
+    pub fn alignment(&self) -> BufferAddress {
+        self.alignment
+    }
+}
+
+/// A range of a [`SharedBufferPool`], returned to the pool when dropped.
+///
+/// A slice is not an `oidn::Buffer` of its own. OIDN can't create buffers viewing part of an
+/// imported buffer, and importing the memory again per slice would need the exported
+/// allocation per slice that pools avoid. Slices instead use the pool's OIDN buffer at their
+/// offset, through [`read`](Self::read), [`write`](Self::write) and
+/// [`set_filter_image`](Self::set_filter_image).
+///
+/// Ownership is tracked for the whole pool, not per slice: while the pool's buffer belongs to
+/// wgpu no slice can be used by OIDN, and the other way around. Hand the pool over once for a
+/// batch of slices, see [`SharedBufferPool::buffer`].
+pub struct SharedBufferSlice<'a> {
+    pool: &'a SharedBufferPool,
+    offset: BufferAddress,
+    size: BufferAddress,
+}
+
+impl<'a> SharedBufferSlice<'a> {
+    pub fn offset(&self) -> BufferAddress {
+        self.offset
+    }
+    pub fn size(&self) -> BufferAddress {
+        self.size
+    }
+
+    /// Reads the slice as `f32`s, failing if wgpu may still use the pool's buffer.
+    ///
+    /// Panics if the size of the slice is not a multiple of 4.
+    pub fn read(&self) -> Result<Vec<f32>, AccessError> {
+        assert_eq!(
+            self.size % size_of::<f32>() as BufferAddress,
+            0,
+            "the slice does not hold whole f32s"
+        );
+        let buffer = self.pool.buffer.checked_oidn_buffer()?;
+        let mut contents = vec![0.0_f32; self.size as usize / size_of::<f32>()];
+        unsafe {
+            oidn::sys::oidnReadBuffer(
+                buffer.raw(),
+                self.offset as usize,
+                size_of_val(contents.as_slice()),
+                contents.as_mut_ptr() as *mut _,
+            )
+        };
+        Ok(contents)
+    }
+
+    /// Writes `contents` to the start of the slice, failing if wgpu may still use the pool's
+    /// buffer.
+    ///
+    /// Panics if `contents` is larger than the slice.
+    pub fn write(&self, contents: &[f32]) -> Result<(), AccessError> {
+        let byte_size = size_of_val(contents);
+        assert!(byte_size as BufferAddress <= self.size);
+        let buffer = self.pool.buffer.checked_oidn_buffer()?;
+        unsafe {
+            oidn::sys::oidnWriteBuffer(
+                buffer.raw(),
+                self.offset as usize,
+                byte_size,
+                contents.as_ptr() as *const _,
+            )
+        };
+        Ok(())
+    }
+
+    /// Binds a tightly packed `width` by `height` image at the start of the slice as the image
+    /// `name` of `filter`, failing if wgpu may still use the pool's buffer.
+    ///
+    /// # Safety
+    /// `filter` must be a valid filter of the OIDN device the pool was created with, and the
+    /// image must fit into the slice.
+    pub unsafe fn set_filter_image(
+        &self,
+        filter: oidn::sys::OIDNFilter,
+        name: &CStr,
+        format: BufferFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<(), AccessError> {
+        debug_assert!(format.buffer_size(width, height) <= self.size);
+        let buffer = self.pool.buffer.checked_oidn_buffer()?;
+        unsafe {
+            oidn::sys::oidnSetFilterImage(
+                filter,
+                name.as_ptr(),
+                buffer.raw(),
+                format.oidn_format(),
+                width as usize,
+                height as usize,
+                self.offset as usize,
+                0,
+                0,
+            )
+        };
+        Ok(())
+    }
+
+    /// The slice of the pool's wgpu buffer, failing if OIDN may still use the pool's buffer.
+    pub fn wgpu_slice(&self) -> Result<wgpu::BufferSlice<'a>, AccessError> {
+        let buffer = self.pool.buffer.checked_wgpu_buffer()?;
+        Ok(buffer.slice(self.offset..self.offset + self.size))
+    }
+}
+
+impl Drop for SharedBufferSlice<'_> {
+    fn drop(&mut self) {
+        self.pool
+            .free_list
+            .lock()
+            .unwrap()
+            .free(self.offset..self.offset + self.size);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_free_list() {
+    let mut free_list = FreeList::new(1024);
+    let a = free_list.allocate(100, 256).unwrap();
+    let b = free_list.allocate(100, 256).unwrap();
+    let c = free_list.allocate(300, 256).unwrap();
+    assert_eq!([a, b, c], [0, 256, 512]);
+    assert_eq!(free_list.allocate(300, 256), None);
+    free_list.free(b..b + 100);
+    assert_eq!(free_list.allocate(200, 256), Some(256));
+    free_list.free(a..a + 100);
+    free_list.free(256..456);
+    free_list.free(c..c + 300);
+    assert_eq!(
+        free_list.free,
+        [Range {
+            start: 0,
+            end: 1024
+        }]
+    );
+}
@@ -0,0 +1,143 @@
+use std::ffi::CStr;
+
+use oidn::sys::{
+    OIDNDeviceType, OIDNDeviceType_OIDN_DEVICE_TYPE_CPU, OIDNDeviceType_OIDN_DEVICE_TYPE_CUDA,
+    OIDNDeviceType_OIDN_DEVICE_TYPE_HIP, OIDNDeviceType_OIDN_DEVICE_TYPE_METAL,
+    OIDNDeviceType_OIDN_DEVICE_TYPE_SYCL,
+};
+
+#[cfg(vulkan)]
+use crate::VulkanSharingMode;
+use crate::{DeviceBuilder, DeviceCreateError, StagingMode};
+
+/// The kind of device OIDN runs on.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum OidnDeviceType {
+    Cpu,
+    Sycl,
+    Cuda,
+    Hip,
+    Metal,
+    /// A device type this version of the library does not know about.
+    Other(OIDNDeviceType),
+}
+
+impl OidnDeviceType {
+    pub(crate) fn of(device: &oidn::Device) -> Self {
+        let ty = unsafe { oidn::sys::oidnGetDeviceInt(device.raw(), b"type\0" as *const _ as _) }
+            as OIDNDeviceType;
+        #[allow(non_upper_case_globals)]
+        match ty {
+            OIDNDeviceType_OIDN_DEVICE_TYPE_CPU => OidnDeviceType::Cpu,
+            OIDNDeviceType_OIDN_DEVICE_TYPE_SYCL => OidnDeviceType::Sycl,
+            OIDNDeviceType_OIDN_DEVICE_TYPE_CUDA => OidnDeviceType::Cuda,
+            OIDNDeviceType_OIDN_DEVICE_TYPE_HIP => OidnDeviceType::Hip,
+            OIDNDeviceType_OIDN_DEVICE_TYPE_METAL => OidnDeviceType::Metal,
+            other => OidnDeviceType::Other(other),
+        }
+    }
+
+    pub(crate) fn raw(self) -> OIDNDeviceType {
+        match self {
+            OidnDeviceType::Cpu => OIDNDeviceType_OIDN_DEVICE_TYPE_CPU,
+            OidnDeviceType::Sycl => OIDNDeviceType_OIDN_DEVICE_TYPE_SYCL,
+            OidnDeviceType::Cuda => OIDNDeviceType_OIDN_DEVICE_TYPE_CUDA,
+            OidnDeviceType::Hip => OIDNDeviceType_OIDN_DEVICE_TYPE_HIP,
+            OidnDeviceType::Metal => OIDNDeviceType_OIDN_DEVICE_TYPE_METAL,
+            OidnDeviceType::Other(ty) => ty,
+        }
+    }
+}
+
+/// Which identifier matched the adapter to an OIDN physical device.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum DeviceIdMatch {
+    Luid,
+    Uuid,
+}
+
+/// What [`probe`] found out about an adapter.
+///
+/// Fields are filled in as far as probing got, so on failure they show which step went wrong.
+#[derive(Debug)]
+pub struct InteropCapabilities {
+    pub backend: wgpu::Backend,
+    /// Whether the device would copy buffers through host memory instead of sharing them, see
+    /// [`StagingMode`]. The other fields still show why sharing failed on a fallback.
+    pub staging: bool,
+    /// The type of the OIDN device matching the adapter, or the staging OIDN device.
+    pub oidn_device_type: Option<OidnDeviceType>,
+    /// The `OIDN_EXTERNAL_MEMORY_TYPE_FLAG_*`s the OIDN device can import.
+    pub oidn_external_memory_types: oidn::sys::OIDNExternalMemoryTypeFlag,
+    /// How the adapter was matched to an OIDN device, [`None`] if no OIDN device matched.
+    pub id_match: Option<DeviceIdMatch>,
+    /// The external memory extensions the Vulkan physical device supports.
+    pub vulkan_extensions: Vec<&'static CStr>,
+    /// The external memory object extensions the GL context supports.
+    #[cfg(gl)]
+    pub gl_extensions: Vec<&'static str>,
+    /// The sharing mode [`Device::new`](crate::Device::new) would use.
+    #[cfg(vulkan)]
+    pub vulkan_sharing_mode: Option<VulkanSharingMode>,
+    /// Why creating a [`Device`](crate::Device) would fail, if it would.
+    pub error: Option<DeviceCreateError>,
+}
+
+impl InteropCapabilities {
+    pub(crate) fn new(backend: wgpu::Backend) -> Self {
+        Self {
+            backend,
+            staging: false,
+            oidn_device_type: None,
+            oidn_external_memory_types: 0,
+            id_match: None,
+            vulkan_extensions: Vec::new(),
+            #[cfg(gl)]
+            gl_extensions: Vec::new(),
+            #[cfg(vulkan)]
+            vulkan_sharing_mode: None,
+            error: None,
+        }
+    }
+
+    /// Whether a [`Device`](crate::Device) can be created for the adapter.
+    pub fn is_supported(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Checks whether interoperability works on `adapter` without creating a wgpu device.
+///
+/// This still creates (and releases) an OIDN device to find out what it supports.
+pub fn probe(adapter: &wgpu::Adapter) -> InteropCapabilities {
+    DeviceBuilder::new().probe(adapter)
+}
+
+impl DeviceBuilder<'_> {
+    /// Like [`probe`], but with the preferences of this builder.
+    pub fn probe(&self, adapter: &wgpu::Adapter) -> InteropCapabilities {
+        let backend = adapter.get_info().backend;
+        let mut capabilities = InteropCapabilities::new(backend);
+        let result: Result<(), DeviceCreateError> = match (self.staging, backend) {
+            #[cfg(gl)]
+            (_, wgpu::Backend::Gl) => crate::Device::probe_gl(adapter, self, &mut capabilities),
+            (StagingMode::Always, _) => crate::Device::probe_staging(self, &mut capabilities),
+            #[cfg(vulkan)]
+            (_, wgpu::Backend::Vulkan) => {
+                crate::Device::probe_vulkan(adapter, self, &mut capabilities)
+            }
+            #[cfg(dx12)]
+            (_, wgpu::Backend::Dx12) => crate::Device::probe_dx12(adapter, self, &mut capabilities),
+            (_, backend) => Err(DeviceCreateError::UnsupportedBackend(backend)),
+        };
+        // the same fallback as `build`
+        let result = match result {
+            Err(err) if self.staging == StagingMode::Fallback && err.allows_staging() => {
+                crate::Device::probe_staging(self, &mut capabilities)
+            }
+            result => result,
+        };
+        capabilities.error = result.err();
+        capabilities
+    }
+}
@@ -0,0 +1,244 @@
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+
+use crate::{Device, DeviceBuilder, InteropCapabilities, OidnDeviceType};
+
+/// What happened to one adapter during [`Device::new_best`].
+#[derive(Debug)]
+pub struct AdapterReport {
+    pub info: wgpu::AdapterInfo,
+    /// The probe results, with [`InteropCapabilities::error`] also set if creating the device
+    /// failed. Supported adapters which simply ranked lower than the chosen one have no error.
+    pub capabilities: InteropCapabilities,
+}
+
+/// The adapter chosen by [`Device::new_best`] and the device created on it.
+pub struct AdapterSelection {
+    pub adapter: wgpu::Adapter,
+    pub device: Device,
+    pub queue: wgpu::Queue,
+    /// A report for every enumerated adapter, including the chosen one.
+    pub reports: Vec<AdapterReport>,
+}
+
+/// No enumerated adapter supports interoperability.
+pub struct NoSuitableAdapter {
+    /// Why each adapter was rejected.
+    pub reports: Vec<AdapterReport>,
+}
+
+impl Display for NoSuitableAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("No adapter supports interoperability")?;
+        for report in &self.reports {
+            f.write_str("\n    ")?;
+            f.write_str(&report.info.name)?;
+            f.write_str(" (")?;
+            Display::fmt(&report.info.backend, f)?;
+            f.write_str("): ")?;
+            match &report.capabilities.error {
+                Some(err) => Display::fmt(err, f)?,
+                None => f.write_str("supported")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for NoSuitableAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for NoSuitableAdapter {}
+
+/// Ranks a supported adapter, lower is better.
+fn rank(
+    device_type: wgpu::DeviceType,
+    capabilities: &InteropCapabilities,
+    builder: &DeviceBuilder<'_>,
+    power_preference: wgpu::PowerPreference,
+) -> (u8, u8, bool, usize) {
+    let device_type = match (device_type, power_preference) {
+        (wgpu::DeviceType::IntegratedGpu, wgpu::PowerPreference::LowPower) => 0,
+        (wgpu::DeviceType::DiscreteGpu, wgpu::PowerPreference::LowPower) => 1,
+        (wgpu::DeviceType::DiscreteGpu, _) => 0,
+        (wgpu::DeviceType::IntegratedGpu, _) => 1,
+        (wgpu::DeviceType::VirtualGpu, _) => 2,
+        (wgpu::DeviceType::Other, _) => 3,
+        (wgpu::DeviceType::Cpu, _) => 4,
+    };
+    // A GPU OIDN device is much faster than running the filter on the CPU.
+    let oidn_device_type = match capabilities.oidn_device_type {
+        Some(OidnDeviceType::Cpu) | None => 1,
+        Some(_) => 0,
+    };
+    // Staging copies every buffer through host memory.
+    let staging = capabilities.staging;
+    #[cfg(vulkan)]
+    let sharing_mode = capabilities
+        .vulkan_sharing_mode
+        .and_then(|mode| {
+            builder
+                .vulkan_sharing_modes
+                .iter()
+                .position(|&other| other == mode)
+        })
+        .unwrap_or(0);
+    #[cfg(not(vulkan))]
+    let sharing_mode = {
+        let _ = builder;
+        0
+    };
+    (device_type, oidn_device_type, staging, sharing_mode)
+}
+
+impl Device {
+    /// Creates a device on the best adapter of `instance` that supports interoperability.
+    ///
+    /// Adapters are ranked by device type (following `power_preference`), then by whether OIDN
+    /// runs on a GPU, then by whether buffers are shared without staging copies, then by sharing
+    /// mode. If creating the device on the best adapter fails the
+    /// next one is tried.
+    pub async fn new_best(
+        instance: &wgpu::Instance,
+        desc: &wgpu::DeviceDescriptor<'_>,
+        power_preference: wgpu::PowerPreference,
+    ) -> Result<AdapterSelection, NoSuitableAdapter> {
+        DeviceBuilder::new()
+            .device_descriptor(desc.clone())
+            .build_best(instance, power_preference)
+            .await
+    }
+}
+
+impl DeviceBuilder<'_> {
+    /// Like [`Device::new_best`], but with the options of this builder.
+    pub async fn build_best(
+        &self,
+        instance: &wgpu::Instance,
+        power_preference: wgpu::PowerPreference,
+    ) -> Result<AdapterSelection, NoSuitableAdapter> {
+        let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+        let mut candidates: Vec<_> = adapters
+            .into_iter()
+            .map(|adapter| {
+                let report = AdapterReport {
+                    info: adapter.get_info(),
+                    capabilities: self.probe(&adapter),
+                };
+                (adapter, report)
+            })
+            .collect();
+        // Unsupported adapters go last, they are only kept for the reports.
+        candidates.sort_by_cached_key(|(_, report)| {
+            (
+                !report.capabilities.is_supported(),
+                rank(
+                    report.info.device_type,
+                    &report.capabilities,
+                    self,
+                    power_preference,
+                ),
+            )
+        });
+
+        let mut selection = None;
+        for (adapter, report) in &mut candidates {
+            if selection.is_some() || !report.capabilities.is_supported() {
+                continue;
+            }
+            match self.build(adapter).await {
+                Ok((device, queue)) => selection = Some((adapter.clone(), device, queue)),
+                Err(err) => report.capabilities.error = Some(err),
+            }
+        }
+        let reports = candidates.into_iter().map(|(_, report)| report).collect();
+        match selection {
+            Some((adapter, device, queue)) => Ok(AdapterSelection {
+                adapter,
+                device,
+                queue,
+                reports,
+            }),
+            None => Err(NoSuitableAdapter { reports }),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_rank() {
+    let mut capabilities = InteropCapabilities::new(wgpu::Backend::Noop);
+    capabilities.oidn_device_type = Some(OidnDeviceType::Cuda);
+    let builder = DeviceBuilder::new();
+    let discrete = rank(
+        wgpu::DeviceType::DiscreteGpu,
+        &capabilities,
+        &builder,
+        wgpu::PowerPreference::HighPerformance,
+    );
+    let integrated = rank(
+        wgpu::DeviceType::IntegratedGpu,
+        &capabilities,
+        &builder,
+        wgpu::PowerPreference::HighPerformance,
+    );
+    assert!(discrete < integrated);
+    let integrated_low_power = rank(
+        wgpu::DeviceType::IntegratedGpu,
+        &capabilities,
+        &builder,
+        wgpu::PowerPreference::LowPower,
+    );
+    let discrete_low_power = rank(
+        wgpu::DeviceType::DiscreteGpu,
+        &capabilities,
+        &builder,
+        wgpu::PowerPreference::LowPower,
+    );
+    assert!(integrated_low_power < discrete_low_power);
+    capabilities.oidn_device_type = Some(OidnDeviceType::Cpu);
+    let discrete_cpu_oidn = rank(
+        wgpu::DeviceType::DiscreteGpu,
+        &capabilities,
+        &builder,
+        wgpu::PowerPreference::HighPerformance,
+    );
+    assert!(discrete < discrete_cpu_oidn);
+}
+
+#[cfg(test)]
+#[test]
+fn test_rank_staging() {
+    let builder = DeviceBuilder::new().staging(crate::StagingMode::Fallback);
+    let mut zero_copy = InteropCapabilities::new(wgpu::Backend::Vulkan);
+    zero_copy.oidn_device_type = Some(OidnDeviceType::Cpu);
+    // an adapter that OIDN can only reach through host memory
+    let mut staging = InteropCapabilities::new(wgpu::Backend::Gl);
+    staging.staging = true;
+    staging.oidn_device_type = Some(OidnDeviceType::Cpu);
+    assert!(staging.is_supported());
+    let zero_copy_rank = rank(
+        wgpu::DeviceType::DiscreteGpu,
+        &zero_copy,
+        &builder,
+        wgpu::PowerPreference::HighPerformance,
+    );
+    let staging_rank = rank(
+        wgpu::DeviceType::DiscreteGpu,
+        &staging,
+        &builder,
+        wgpu::PowerPreference::HighPerformance,
+    );
+    assert!(zero_copy_rank < staging_rank);
+    // staging still beats a worse device type
+    let integrated_rank = rank(
+        wgpu::DeviceType::IntegratedGpu,
+        &zero_copy,
+        &builder,
+        wgpu::PowerPreference::HighPerformance,
+    );
+    assert!(staging_rank < integrated_rank);
+}
@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::sync::mpsc;
+
+use wgpu::util::align_to;
+use wgpu::{BufferDescriptor, BufferUsages, COPY_BUFFER_ALIGNMENT};
+
+use crate::{
+    DeviceBuilder, DeviceCreateError, MemoryLocation, SharedBuffer, SharedBufferCreateError,
+    SharedBufferDescriptor,
+};
+
+/// Whether a [`Device`](crate::Device) may copy through the CPU instead of sharing memory.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum StagingMode {
+    /// Only create zero-copy devices.
+    #[default]
+    Never,
+    /// Create a staging device if the adapter or OIDN can't share memory.
+    Fallback,
+    /// Always create a staging device.
+    Always,
+}
+
+impl DeviceCreateError {
+    /// Whether this error means that memory can't be shared, as opposed to device creation
+    /// failing outright.
+    pub(crate) fn allows_staging(&self) -> bool {
+        matches!(
+            self,
+            DeviceCreateError::OidnUnsupported
+                | DeviceCreateError::OidnImportUnsupported
+                | DeviceCreateError::MissingFeature
+                | DeviceCreateError::MissingDeviceExtensions(_)
+                | DeviceCreateError::UnsupportedBackend(_)
+        )
+    }
+}
+
+/// An error from [`Device::sync_to_oidn`](crate::Device::sync_to_oidn),
+/// [`Device::sync_to_wgpu`](crate::Device::sync_to_wgpu) or the `acquire` functions.
+pub enum SyncError {
+    Poll(wgpu::PollError),
+    Map(wgpu::BufferAsyncError),
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Poll(err) => {
+                f.write_str("Waiting for wgpu failed: ")?;
+                Display::fmt(err, f)
+            }
+            SyncError::Map(err) => {
+                f.write_str("Mapping the staging buffer failed: ")?;
+                Display::fmt(err, f)
+            }
+        }
+    }
+}
+
+impl Debug for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for SyncError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SyncError::Poll(err) => Some(err),
+            SyncError::Map(err) => Some(err),
+        }
+    }
+}
+
+impl From<wgpu::PollError> for SyncError {
+    fn from(err: wgpu::PollError) -> Self {
+        SyncError::Poll(err)
+    }
+}
+
+impl From<wgpu::BufferAsyncError> for SyncError {
+    fn from(err: wgpu::BufferAsyncError) -> Self {
+        SyncError::Map(err)
+    }
+}
+
+pub(crate) struct StagingAllocation {
+    readback: wgpu::Buffer,
+}
+
+impl crate::Device {
+    /// Creates a device that copies between wgpu and OIDN through the CPU.
+    pub(crate) async fn new_staging(
+        adapter: &wgpu::Adapter,
+        builder: &DeviceBuilder<'_>,
+    ) -> Result<(Self, wgpu::Queue), DeviceCreateError> {
+        let oidn_device = Self::staging_oidn_device(builder)?;
+        let (wgpu_device, queue) = adapter.request_device(&builder.desc).await?;
+        Ok((
+            Self {
+                wgpu_device,
+                oidn_device: oidn_device.into(),
+                queue: queue.clone(),
+                backend_data: crate::BackendData::Staging,
+                converter: Default::default(),
+                worker: Default::default(),
+            },
+            queue,
+        ))
+    }
+
+    pub(crate) fn from_existing_staging(
+        builder: &DeviceBuilder<'_>,
+        wgpu_device: wgpu::Device,
+        queue: wgpu::Queue,
+    ) -> Result<Self, DeviceCreateError> {
+        Ok(Self {
+            oidn_device: Self::staging_oidn_device(builder)?.into(),
+            wgpu_device,
+            queue,
+            backend_data: crate::BackendData::Staging,
+            converter: Default::default(),
+            worker: Default::default(),
+        })
+    }
+
+    fn staging_oidn_device(builder: &DeviceBuilder<'_>) -> Result<oidn::Device, DeviceCreateError> {
+        let device = unsafe { oidn::sys::oidnNewDevice(builder.staging_oidn_device_type.raw()) };
+        Self::oidn_device_from_raw(device, builder, |_| Ok(())).map(|(device, ())| device)
+    }
+
+    pub(crate) fn probe_staging(
+        builder: &DeviceBuilder<'_>,
+        capabilities: &mut crate::InteropCapabilities,
+    ) -> Result<(), DeviceCreateError> {
+        capabilities.staging = true;
+        let oidn_device = Self::staging_oidn_device(builder)?;
+        capabilities.oidn_device_type = Some(crate::OidnDeviceType::of(&oidn_device));
+        Ok(())
+    }
+
+    /// Whether buffers are shared without copies, or copied through the CPU by
+    /// [`Device::sync_to_oidn`](crate::Device::sync_to_oidn) and
+    /// [`Device::sync_to_wgpu`](crate::Device::sync_to_wgpu).
+    pub fn is_zero_copy(&self) -> bool {
+        self.backend_data.as_backend() != crate::Backend::Staging
+    }
+
+    pub(crate) fn allocate_shared_buffers_staging(
+        &self,
+        desc: &SharedBufferDescriptor<'_>,
+    ) -> Result<SharedBuffer, SharedBufferCreateError> {
+        let size = desc.size;
+        let storage = match desc.memory_location {
+            MemoryLocation::HostVisible => oidn::sys::OIDNStorage_OIDN_STORAGE_HOST,
+            MemoryLocation::DeviceLocal | MemoryLocation::Auto => {
+                oidn::sys::OIDNStorage_OIDN_STORAGE_DEVICE
+            }
+        };
+        let oidn_buffer = unsafe {
+            oidn::sys::oidnNewBufferWithStorage(self.oidn_device.raw(), size as usize, storage)
+        };
+        if oidn_buffer.is_null() {
+            let err = self.oidn_device.get_error().unwrap_err();
+            trace_event!(warn, "Failed to create oidn buffer: {:?}", err);
+            return Err(SharedBufferCreateError::Oidn(err));
+        }
+        let oidn_buffer = unsafe { self.oidn_device.create_buffer_from_raw(oidn_buffer) };
+        // copies must be a multiple of `COPY_BUFFER_ALIGNMENT`
+        let aligned_size = align_to(size, COPY_BUFFER_ALIGNMENT);
+        let wgpu_buffer = self.wgpu_device.create_buffer(&BufferDescriptor {
+            label: desc.label,
+            size: aligned_size,
+            usage: desc.usage,
+            mapped_at_creation: false,
+        });
+        let readback = self.wgpu_device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: aligned_size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Ok(SharedBuffer {
+            allocation: crate::Allocation::Staging(StagingAllocation { readback }),
+            oidn_buffer,
+            wgpu_buffer,
+            owner: Default::default(),
+            device: self.clone(),
+        })
+    }
+
+    /// Makes wgpu's writes to `buffer` visible to OIDN.
+    ///
+    /// Waits for all submitted wgpu work, then on a staging device copies the contents into the
+    /// OIDN buffer.
+    pub fn sync_to_oidn(&self, buffer: &SharedBuffer) -> Result<(), SyncError> {
+        self.wait_for_wgpu(buffer, None)?;
+        buffer.owner.set_oidn();
+        Ok(())
+    }
+
+    /// Makes OIDN's writes to `buffer` visible to wgpu.
+    ///
+    /// Waits for all OIDN work, then on a staging device queues a write of the contents to the
+    /// wgpu buffer. Work submitted to the queue afterwards sees the new contents.
+    pub fn sync_to_wgpu(&self, buffer: &SharedBuffer) -> Result<(), SyncError> {
+        self.wait_for_oidn(buffer)?;
+        buffer.owner.set_wgpu();
+        Ok(())
+    }
+
+    /// Waits for `submission`, or all submitted work without one, and copies the contents into
+    /// the OIDN buffer on a staging device.
+    pub(crate) fn wait_for_wgpu(
+        &self,
+        buffer: &SharedBuffer,
+        submission: Option<wgpu::SubmissionIndex>,
+    ) -> Result<(), SyncError> {
+        self.start_wait_for_wgpu(buffer, submission)
+            .finish(&self.wgpu_device)
+    }
+
+    /// Starts [`Device::wait_for_wgpu`](crate::Device::wait_for_wgpu) without blocking, by
+    /// submitting the copy into the readback buffer on a staging device. The wait can then be
+    /// finished on another thread.
+    pub(crate) fn start_wait_for_wgpu(
+        &self,
+        buffer: &SharedBuffer,
+        submission: Option<wgpu::SubmissionIndex>,
+    ) -> WgpuWait {
+        // only staging allocations exist without a zero-copy backend
+        #[cfg_attr(not(any(dx12, vulkan)), allow(irrefutable_let_patterns))]
+        let crate::Allocation::Staging(staging) = &buffer.allocation else {
+            return WgpuWait {
+                submission,
+                staging: None,
+            };
+        };
+        let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(&buffer.wgpu_buffer, 0, &staging.readback, 0, None);
+        let (send, recv) = mpsc::channel();
+        encoder.map_buffer_on_submit(&staging.readback, wgpu::MapMode::Read, .., move |res| {
+            // the receiver is only dropped after this is called, or with the device
+            let _ = send.send(res);
+        });
+        // the copy comes after `submission`, so waiting for it is enough
+        let copy = self.queue.submit([encoder.finish()]);
+        WgpuWait {
+            submission: Some(copy),
+            staging: Some(StagingWait {
+                readback: staging.readback.clone(),
+                oidn_buffer: self.retain_oidn_buffer(&buffer.oidn_buffer),
+                mapped: recv,
+            }),
+        }
+    }
+
+    /// Waits for all OIDN work and queues a write of the contents to the wgpu buffer on a
+    /// staging device.
+    pub(crate) fn wait_for_oidn(&self, buffer: &SharedBuffer) -> Result<(), SyncError> {
+        unsafe { oidn::sys::oidnSyncDevice(self.oidn_device.raw()) };
+        if !matches!(buffer.allocation, crate::Allocation::Staging(_)) {
+            return Ok(());
+        }
+        let size = unsafe { oidn::sys::oidnGetBufferSize(buffer.oidn_buffer.raw()) };
+        let mut contents = vec![0; buffer.wgpu_buffer.size() as usize];
+        unsafe {
+            oidn::sys::oidnReadBuffer(
+                buffer.oidn_buffer.raw(),
+                0,
+                size,
+                contents.as_mut_ptr() as *mut _,
+            )
+        };
+        self.queue.write_buffer(&buffer.wgpu_buffer, 0, &contents);
+        Ok(())
+    }
+}
+
+/// A wait for wgpu started by [`Device::start_wait_for_wgpu`](crate::Device), which owns what
+/// it needs to finish on any thread.
+pub(crate) struct WgpuWait {
+    submission: Option<wgpu::SubmissionIndex>,
+    staging: Option<StagingWait>,
+}
+
+/// The copy of a staging buffer's contents on its way to the OIDN buffer.
+struct StagingWait {
+    readback: wgpu::Buffer,
+    oidn_buffer: oidn::Buffer,
+    mapped: mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>,
+}
+
+impl WgpuWait {
+    /// Waits for the submission, then on a staging device copies the contents from the
+    /// readback buffer into the OIDN buffer.
+    pub(crate) fn finish(self, device: &wgpu::Device) -> Result<(), SyncError> {
+        device.poll(wgpu::PollType::Wait {
+            submission_index: self.submission,
+            timeout: None,
+        })?;
+        let Some(staging) = self.staging else {
+            return Ok(());
+        };
+        // waiting for the copy called the callback
+        staging.mapped.recv().unwrap()?;
+        {
+            let view = staging.readback.get_mapped_range(..);
+            let size = unsafe { oidn::sys::oidnGetBufferSize(staging.oidn_buffer.raw()) };
+            unsafe {
+                oidn::sys::oidnWriteBuffer(
+                    staging.oidn_buffer.raw(),
+                    0,
+                    size,
+                    view.as_ptr() as *const _,
+                )
+            };
+        }
+        staging.readback.unmap();
+        Ok(())
+    }
+}
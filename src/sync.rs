@@ -0,0 +1,410 @@
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::ops::{Deref, DerefMut, RangeBounds};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{Device, SharedBuffer, SharedTexture, SyncError};
+
+// the states of an `Ownership`, untracked until either API first uses the buffer
+const UNTRACKED: u8 = 0;
+const RELEASED_TO_OIDN: u8 = 1;
+const OIDN: u8 = 2;
+const RELEASED_TO_WGPU: u8 = 3;
+const WGPU: u8 = 4;
+
+/// Which API a [`SharedBuffer`] or [`SharedTexture`] was last handed to, to refuse access guards while the other
+/// API may still use it and to report misuse of the transfers in debug builds.
+#[derive(Default)]
+pub(crate) struct Ownership(AtomicU8);
+
+impl Ownership {
+    fn try_transition(&self, from: &[u8], to: u8) -> Result<(), AccessError> {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                from.contains(&state).then_some(to)
+            })
+            .map(drop)
+            .map_err(|state| match state {
+                RELEASED_TO_OIDN | RELEASED_TO_WGPU => AccessError::Released,
+                OIDN => AccessError::OwnedByOidn,
+                _ => AccessError::OwnedByWgpu,
+            })
+    }
+
+    fn transition(&self, from: &[u8], to: u8, action: &str) {
+        let previous = self.0.swap(to, Ordering::Relaxed);
+        debug_assert!(
+            from.contains(&previous),
+            "{action} a shared buffer that {}",
+            match previous {
+                UNTRACKED => "was never released",
+                RELEASED_TO_OIDN => "was released to OIDN but not acquired",
+                OIDN => "is owned by OIDN",
+                RELEASED_TO_WGPU => "was released to wgpu but not acquired",
+                _ => "is owned by wgpu",
+            }
+        );
+    }
+
+    /// Claims the buffer for OIDN, failing if wgpu may still use it.
+    pub(crate) fn claim_oidn(&self) -> Result<(), AccessError> {
+        self.try_transition(&[UNTRACKED, OIDN], OIDN)
+    }
+
+    /// Claims the buffer for wgpu, failing if OIDN may still use it.
+    pub(crate) fn claim_wgpu(&self) -> Result<(), AccessError> {
+        self.try_transition(&[UNTRACKED, WGPU], WGPU)
+    }
+
+    /// Releases the buffer to OIDN before a wait for the wgpu work using it, failing if it was
+    /// released and not acquired. A buffer OIDN owns already is released again.
+    pub(crate) fn release_oidn(&self) -> Result<(), AccessError> {
+        self.try_transition(&[UNTRACKED, WGPU, OIDN], RELEASED_TO_OIDN)
+    }
+
+    /// Hands a buffer released with [`Ownership::release_oidn`] to OIDN after the wait for the
+    /// wgpu work using it, failing if wgpu may still use it.
+    pub(crate) fn acquire_oidn(&self) -> Result<(), AccessError> {
+        self.try_transition(&[UNTRACKED, RELEASED_TO_OIDN], OIDN)
+    }
+
+    /// Hands the buffer to wgpu after a wait for the OIDN work using it, failing if it was
+    /// released and not acquired.
+    pub(crate) fn acquire_wgpu(&self) -> Result<(), AccessError> {
+        self.try_transition(&[UNTRACKED, OIDN, WGPU], WGPU)
+    }
+
+    fn is_untracked(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == UNTRACKED
+    }
+
+    /// Hands the buffer to OIDN after a synchronisation that waited for all wgpu work.
+    pub(crate) fn set_oidn(&self) {
+        self.0.store(OIDN, Ordering::Relaxed);
+    }
+
+    /// Hands the buffer to wgpu after a synchronisation that waited for all OIDN work.
+    pub(crate) fn set_wgpu(&self) {
+        self.0.store(WGPU, Ordering::Relaxed);
+    }
+}
+
+/// Why [`SharedBuffer::oidn_access`] or [`SharedBuffer::wgpu_access`] refused access.
+pub enum AccessError {
+    /// wgpu may still use the buffer, hand it to OIDN with [`Device::sync_to_oidn`] or
+    /// [`Device::acquire_for_oidn`] first.
+    OwnedByWgpu,
+    /// OIDN may still use the buffer, hand it to wgpu with [`Device::sync_to_wgpu`] or
+    /// [`Device::acquire_for_wgpu`] first.
+    OwnedByOidn,
+    /// The buffer was released and is not acquired yet.
+    Released,
+    /// Waiting for the wgpu work on a buffer that was never handed over failed.
+    Sync(SyncError),
+}
+
+impl Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AccessError::OwnedByWgpu => "The shared buffer is owned by wgpu",
+            AccessError::OwnedByOidn => "The shared buffer is owned by OIDN",
+            AccessError::Released => "The shared buffer was released but not acquired",
+            AccessError::Sync(err) => return Display::fmt(err, f),
+        })
+    }
+}
+
+impl Debug for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for AccessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AccessError::Sync(err) => Some(err),
+            AccessError::OwnedByWgpu | AccessError::OwnedByOidn | AccessError::Released => None,
+        }
+    }
+}
+
+/// The OIDN buffer of a [`SharedBuffer`] or [`SharedTexture`] that wgpu is done with.
+///
+/// The guard borrows the shared buffer or texture mutably, so its wgpu side can't be used while
+/// it is held. Afterwards it stays owned by OIDN until it is handed back to wgpu.
+pub struct OidnAccess<'a> {
+    oidn_buffer: &'a mut oidn::Buffer,
+}
+
+impl Deref for OidnAccess<'_> {
+    type Target = oidn::Buffer;
+
+    fn deref(&self) -> &oidn::Buffer {
+        self.oidn_buffer
+    }
+}
+
+impl DerefMut for OidnAccess<'_> {
+    fn deref_mut(&mut self) -> &mut oidn::Buffer {
+        self.oidn_buffer
+    }
+}
+
+/// The wgpu buffer of a [`SharedBuffer`] that OIDN is done with.
+///
+/// The guard borrows the shared buffer mutably, so its OIDN buffer can't be used while it is
+/// held. Afterwards the buffer stays owned by wgpu until it is handed back to OIDN. The
+/// [`wgpu::Buffer`] itself isn't handed out, a clone of it would outlive the guard.
+pub struct WgpuAccess<'a> {
+    buffer: &'a mut SharedBuffer,
+}
+
+impl WgpuAccess<'_> {
+    /// A slice of the buffer, see [`wgpu::Buffer::slice`].
+    pub fn slice<S: RangeBounds<wgpu::BufferAddress>>(&self, bounds: S) -> wgpu::BufferSlice<'_> {
+        self.buffer.wgpu_buffer.slice(bounds)
+    }
+
+    /// Binds the whole buffer, see [`wgpu::Buffer::as_entire_binding`].
+    pub fn as_entire_binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.wgpu_buffer.as_entire_binding()
+    }
+
+    pub fn size(&self) -> wgpu::BufferAddress {
+        self.buffer.wgpu_buffer.size()
+    }
+
+    pub fn usage(&self) -> wgpu::BufferUsages {
+        self.buffer.wgpu_buffer.usage()
+    }
+
+    /// Schedules a write of `data` at `offset` on `queue`, see [`wgpu::Queue::write_buffer`].
+    pub fn write(&self, queue: &wgpu::Queue, offset: wgpu::BufferAddress, data: &[u8]) {
+        queue.write_buffer(&self.buffer.wgpu_buffer, offset, data);
+    }
+
+    /// Records a copy of `size` bytes, or the rest of the buffer, from `offset` to
+    /// `destination`, see [`wgpu::CommandEncoder::copy_buffer_to_buffer`].
+    pub fn encode_copy_to_buffer(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        offset: wgpu::BufferAddress,
+        destination: &wgpu::Buffer,
+        destination_offset: wgpu::BufferAddress,
+        size: impl Into<Option<wgpu::BufferAddress>>,
+    ) {
+        encoder.copy_buffer_to_buffer(
+            &self.buffer.wgpu_buffer,
+            offset,
+            destination,
+            destination_offset,
+            size,
+        );
+    }
+
+    /// Destroys the wgpu buffer, see [`wgpu::Buffer::destroy`]. The OIDN buffer stays usable.
+    pub fn destroy(&self) {
+        self.buffer.wgpu_buffer.destroy();
+    }
+}
+
+/// The wgpu texture of a [`SharedTexture`] that OIDN is done with, like [`WgpuAccess`].
+pub struct WgpuTextureAccess<'a> {
+    texture: &'a mut SharedTexture,
+}
+
+impl WgpuTextureAccess<'_> {
+    /// The whole texture as the source or destination of a copy, see
+    /// [`wgpu::Texture::as_image_copy`].
+    pub fn as_image_copy(&self) -> wgpu::TexelCopyTextureInfo<'_> {
+        self.texture.wgpu_texture.as_image_copy()
+    }
+
+    /// See [`wgpu::Texture::create_view`].
+    pub fn create_view(&self, desc: &wgpu::TextureViewDescriptor<'_>) -> wgpu::TextureView {
+        self.texture.wgpu_texture.create_view(desc)
+    }
+
+    pub fn size(&self) -> wgpu::Extent3d {
+        self.texture.wgpu_texture.size()
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.texture.wgpu_texture.format()
+    }
+
+    pub fn usage(&self) -> wgpu::TextureUsages {
+        self.texture.wgpu_texture.usage()
+    }
+}
+
+/// A [`SharedBuffer`] handed from wgpu to OIDN, which OIDN may use after
+/// [`Device::acquire_for_oidn`].
+#[must_use = "OIDN may only use the buffer after `Device::acquire_for_oidn`"]
+pub struct ReleasedToOidn<'a> {
+    buffer: &'a SharedBuffer,
+    submission: wgpu::SubmissionIndex,
+}
+
+/// A [`SharedBuffer`] handed from OIDN to wgpu, which wgpu may use after
+/// [`Device::acquire_for_wgpu`].
+#[must_use = "wgpu may only use the buffer after `Device::acquire_for_wgpu`"]
+pub struct ReleasedToWgpu<'a> {
+    buffer: &'a SharedBuffer,
+}
+
+impl SharedBuffer {
+    /// Accesses the OIDN buffer, failing if wgpu may still use it.
+    ///
+    /// A buffer that was never handed to either API first waits for all submitted wgpu work,
+    /// which includes the clear of its allocation. The buffer is owned by OIDN afterwards, until
+    /// [`Device::sync_to_wgpu`] or [`Device::acquire_for_wgpu`] hands it to wgpu.
+    pub fn oidn_access(&mut self) -> Result<OidnAccess<'_>, AccessError> {
+        self.checked_oidn_buffer()?;
+        Ok(OidnAccess {
+            oidn_buffer: &mut self.oidn_buffer,
+        })
+    }
+
+    /// Accesses the wgpu buffer, failing if OIDN may still use it.
+    ///
+    /// The buffer is owned by wgpu afterwards, until [`Device::sync_to_oidn`] or
+    /// [`Device::acquire_for_oidn`] hands it to OIDN.
+    pub fn wgpu_access(&mut self) -> Result<WgpuAccess<'_>, AccessError> {
+        self.checked_wgpu_buffer()?;
+        Ok(WgpuAccess { buffer: self })
+    }
+
+    /// The OIDN buffer for the crate's own use, claimed like [`SharedBuffer::oidn_access`].
+    pub(crate) fn checked_oidn_buffer(&self) -> Result<&oidn::Buffer, AccessError> {
+        if self.owner.is_untracked() {
+            self.device
+                .wait_for_wgpu(self, None)
+                .map_err(AccessError::Sync)?;
+        }
+        self.owner.claim_oidn()?;
+        Ok(&self.oidn_buffer)
+    }
+
+    /// The wgpu buffer for the crate's own use, claimed like [`SharedBuffer::wgpu_access`].
+    pub(crate) fn checked_wgpu_buffer(&self) -> Result<&wgpu::Buffer, AccessError> {
+        self.owner.claim_wgpu()?;
+        Ok(&self.wgpu_buffer)
+    }
+
+    /// Hands the buffer to OIDN once the wgpu work up to `submission` is done, the last
+    /// submission using the buffer.
+    ///
+    /// In debug builds this panics if the buffer was released to either API and not acquired,
+    /// or is owned by OIDN.
+    pub fn release_to_oidn(&self, submission: wgpu::SubmissionIndex) -> ReleasedToOidn<'_> {
+        self.owner
+            .transition(&[UNTRACKED, WGPU], RELEASED_TO_OIDN, "Released to OIDN");
+        ReleasedToOidn {
+            buffer: self,
+            submission,
+        }
+    }
+
+    /// Hands the buffer to wgpu once the OIDN work using it is done.
+    ///
+    /// In debug builds this panics if the buffer was released to either API and not acquired,
+    /// or is owned by wgpu.
+    pub fn release_to_wgpu(&self) -> ReleasedToWgpu<'_> {
+        self.owner
+            .transition(&[UNTRACKED, OIDN], RELEASED_TO_WGPU, "Released to wgpu");
+        ReleasedToWgpu { buffer: self }
+    }
+}
+
+impl SharedTexture {
+    /// Accesses the OIDN buffer, failing if wgpu may still use the texture.
+    ///
+    /// Like [`SharedBuffer::oidn_access`], a texture that was never handed to either API first
+    /// waits for all submitted wgpu work. [`Device::sync_texture_to_oidn`] and
+    /// [`Device::sync_texture_to_wgpu`] hand it between the APIs.
+    pub fn oidn_access(&mut self) -> Result<OidnAccess<'_>, AccessError> {
+        self.checked_oidn_buffer()?;
+        Ok(OidnAccess {
+            oidn_buffer: &mut self.oidn_buffer,
+        })
+    }
+
+    /// Accesses the wgpu texture, failing if OIDN may still use it.
+    pub fn wgpu_access(&mut self) -> Result<WgpuTextureAccess<'_>, AccessError> {
+        self.owner.claim_wgpu()?;
+        Ok(WgpuTextureAccess { texture: self })
+    }
+
+    /// The OIDN buffer for the crate's own use, claimed like [`SharedTexture::oidn_access`].
+    pub(crate) fn checked_oidn_buffer(&self) -> Result<&oidn::Buffer, AccessError> {
+        if self.owner.is_untracked() {
+            self.device
+                .wgpu_device
+                .poll(wgpu::PollType::wait_indefinitely())
+                .map_err(|err| AccessError::Sync(err.into()))?;
+        }
+        self.owner.claim_oidn()?;
+        Ok(&self.oidn_buffer)
+    }
+}
+
+impl Device {
+    /// Waits for the submission the buffer was released with, then on a staging device copies
+    /// the contents into the OIDN buffer.
+    ///
+    /// Unlike [`Device::sync_to_oidn`] this doesn't wait for later submissions.
+    pub fn acquire_for_oidn(&self, released: ReleasedToOidn<'_>) -> Result<(), SyncError> {
+        let buffer = released.buffer;
+        buffer
+            .owner
+            .transition(&[RELEASED_TO_OIDN], OIDN, "Acquired for OIDN");
+        self.wait_for_wgpu(buffer, Some(released.submission))
+    }
+
+    /// Waits for OIDN, then on a staging device queues a write of the contents to the wgpu
+    /// buffer, like [`Device::sync_to_wgpu`].
+    pub fn acquire_for_wgpu(&self, released: ReleasedToWgpu<'_>) -> Result<(), SyncError> {
+        let buffer = released.buffer;
+        buffer
+            .owner
+            .transition(&[RELEASED_TO_WGPU], WGPU, "Acquired for wgpu");
+        self.wait_for_oidn(buffer)
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+#[test]
+fn test_ownership_transitions() {
+    let owner = Ownership::default();
+    owner.transition(&[UNTRACKED, WGPU], RELEASED_TO_OIDN, "Released to OIDN");
+    owner.transition(&[RELEASED_TO_OIDN], OIDN, "Acquired for OIDN");
+    owner.transition(&[UNTRACKED, OIDN], RELEASED_TO_WGPU, "Released to wgpu");
+    owner.transition(&[RELEASED_TO_WGPU], WGPU, "Acquired for wgpu");
+    owner.transition(&[UNTRACKED, WGPU], RELEASED_TO_OIDN, "Released to OIDN");
+    // releasing again without acquiring
+    let result = std::panic::catch_unwind(|| {
+        owner.transition(&[UNTRACKED, WGPU], RELEASED_TO_OIDN, "Released to OIDN")
+    });
+    assert!(result.is_err());
+    assert!(matches!(
+        owner.try_transition(&[UNTRACKED, OIDN], OIDN),
+        Err(AccessError::Released)
+    ));
+    owner.set_oidn();
+    assert!(owner.try_transition(&[UNTRACKED, OIDN], OIDN).is_ok());
+    assert!(matches!(
+        owner.try_transition(&[UNTRACKED, WGPU], WGPU),
+        Err(AccessError::OwnedByOidn)
+    ));
+    owner.transition(&[UNTRACKED, OIDN], RELEASED_TO_WGPU, "Released to wgpu");
+    owner.transition(&[RELEASED_TO_WGPU], WGPU, "Acquired for wgpu");
+    // wgpu never released the buffer, so OIDN can't acquire it
+    assert!(matches!(
+        owner.acquire_oidn(),
+        Err(AccessError::OwnedByWgpu)
+    ));
+    assert!(owner.release_oidn().is_ok());
+    assert!(owner.acquire_oidn().is_ok());
+}
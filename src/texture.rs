@@ -0,0 +1,223 @@
+use std::ffi::CStr;
+
+use wgpu::{TextureFormat, TextureUsages};
+
+use crate::sync::Ownership;
+use crate::{AccessError, Allocation, Backend, Device, SharedBufferCreateError, SyncError};
+
+/// Describes a [`SharedTexture`].
+#[derive(Clone, Debug)]
+pub struct SharedTextureDescriptor<'a> {
+    /// Debug label of the texture, also given to the Vulkan or D3D12 objects backing it.
+    pub label: wgpu::Label<'a>,
+    pub width: u32,
+    pub height: u32,
+    /// One of [`SharedTextureDescriptor::SUPPORTED_FORMATS`].
+    pub format: TextureFormat,
+    pub usage: TextureUsages,
+}
+
+impl SharedTextureDescriptor<'_> {
+    /// The formats a shared texture may have, OIDN reads the first three channels.
+    pub const SUPPORTED_FORMATS: [TextureFormat; 2] =
+        [TextureFormat::Rgba32Float, TextureFormat::Rgba16Float];
+
+    /// The usages a shared texture may have. Whether linear textures support them also depends
+    /// on the adapter and the format.
+    pub const SUPPORTED_USAGES: TextureUsages = TextureUsages::COPY_SRC
+        .union(TextureUsages::COPY_DST)
+        .union(TextureUsages::TEXTURE_BINDING)
+        .union(TextureUsages::STORAGE_BINDING);
+
+    #[cfg_attr(not(any(dx12, vulkan)), allow(dead_code))]
+    pub(crate) fn size(&self) -> wgpu::Extent3d {
+        wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: 1,
+        }
+    }
+
+    #[cfg(any(dx12, vulkan))]
+    pub(crate) fn wgpu_descriptor(&self) -> wgpu::TextureDescriptor<'_> {
+        wgpu::TextureDescriptor {
+            label: self.label,
+            size: self.size(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: self.usage,
+            view_formats: &[],
+        }
+    }
+}
+
+/// Where the texels of a [`SharedTexture`] are in its OIDN buffer, in the terms of
+/// `oidnSetFilterImage`.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct SharedTextureLayout {
+    pub width: u32,
+    pub height: u32,
+    /// The OIDN format of the color channels of a texel.
+    pub format: oidn::sys::OIDNFormat,
+    /// `byteOffset`, where the first row starts.
+    pub byte_offset: usize,
+    /// `pixelByteStride`, the size of a texel including the unused alpha channel.
+    pub pixel_byte_stride: usize,
+    /// `rowByteStride`, the row pitch the driver chose.
+    pub row_byte_stride: usize,
+}
+
+impl SharedTextureLayout {
+    #[cfg(any(dx12, vulkan))]
+    pub(crate) fn new(
+        desc: &SharedTextureDescriptor<'_>,
+        byte_offset: u64,
+        row_byte_stride: u64,
+    ) -> Self {
+        let format = match desc.format {
+            TextureFormat::Rgba32Float => oidn::sys::OIDNFormat_OIDN_FORMAT_FLOAT3,
+            TextureFormat::Rgba16Float => oidn::sys::OIDNFormat_OIDN_FORMAT_HALF3,
+            _ => unreachable!("format was validated"),
+        };
+        Self {
+            width: desc.width,
+            height: desc.height,
+            format,
+            byte_offset: byte_offset as usize,
+            pixel_byte_stride: desc.format.block_copy_size(None).unwrap() as usize,
+            row_byte_stride: row_byte_stride as usize,
+        }
+    }
+}
+
+/// A linear texture whose memory is shared between wgpu and OIDN.
+///
+/// OIDN sees the texture as a buffer, [`SharedTexture::layout`] says where the texels are. The
+/// texels are only there while the texture is in the layout
+/// [`SharedTexture::encode_oidn_layout`] transitions it to.
+pub struct SharedTexture {
+    pub(crate) _allocation: Allocation,
+    pub(crate) oidn_buffer: oidn::Buffer,
+    pub(crate) wgpu_texture: wgpu::Texture,
+    pub(crate) layout: SharedTextureLayout,
+    pub(crate) owner: Ownership,
+    pub(crate) device: Device,
+}
+
+impl SharedTexture {
+    /// The OIDN buffer, without checking that wgpu is done with it like
+    /// [`SharedTexture::oidn_access`].
+    #[deprecated(note = "use `oidn_access`, which checks that wgpu is done with the texture")]
+    pub fn oidn_buffer(&self) -> &oidn::Buffer {
+        &self.oidn_buffer
+    }
+    /// The wgpu texture, without checking that OIDN is done with it like
+    /// [`SharedTexture::wgpu_access`].
+    #[deprecated(note = "use `wgpu_access`, which checks that OIDN is done with the texture")]
+    pub fn wgpu_texture(&self) -> &wgpu::Texture {
+        &self.wgpu_texture
+    }
+    pub fn layout(&self) -> SharedTextureLayout {
+        self.layout
+    }
+
+    /// Records the transition of the texture into the layout OIDN can address its texels in.
+    ///
+    /// Vulkan only defines the memory of a linear image in the `GENERAL` layout, while wgpu moves
+    /// textures into other layouts to copy or sample them. Record this after the last wgpu use
+    /// of the texture before OIDN reads or writes its buffer, wgpu moves the texture out of the
+    /// layout again when it next uses it. D3D12 needs no transition for row major textures.
+    #[cfg_attr(not(vulkan), allow(unused_variables))]
+    pub fn encode_oidn_layout(&self, encoder: &mut wgpu::CommandEncoder) {
+        #[cfg(vulkan)]
+        if self.device.backend_data.as_backend() == Backend::Vulkan {
+            // wgpu keeps color textures in `GENERAL` while shaders write them
+            encoder.transition_resources(
+                std::iter::empty(),
+                std::iter::once(wgpu::TextureTransition {
+                    texture: &self.wgpu_texture,
+                    selector: None,
+                    state: wgpu::TextureUses::STORAGE_READ_WRITE,
+                }),
+            );
+        }
+    }
+
+    /// Binds the texture as the image `name` of `filter`, failing if wgpu may still use it.
+    ///
+    /// # Safety
+    /// `filter` must be a valid filter of the OIDN device the texture was created with.
+    pub unsafe fn set_filter_image(
+        &self,
+        filter: oidn::sys::OIDNFilter,
+        name: &CStr,
+    ) -> Result<(), AccessError> {
+        let layout = self.layout;
+        let buffer = self.checked_oidn_buffer()?;
+        unsafe {
+            oidn::sys::oidnSetFilterImage(
+                filter,
+                name.as_ptr(),
+                buffer.raw(),
+                layout.format,
+                layout.width as usize,
+                layout.height as usize,
+                layout.byte_offset,
+                layout.pixel_byte_stride,
+                layout.row_byte_stride,
+            )
+        };
+        Ok(())
+    }
+}
+
+impl Device {
+    /// Creates a linear texture and an OIDN buffer sharing its memory.
+    ///
+    /// Textures can't be shared on a staging device.
+    pub fn allocate_shared_texture(
+        &self,
+        desc: &SharedTextureDescriptor<'_>,
+    ) -> Result<SharedTexture, SharedBufferCreateError> {
+        if desc.width == 0 || desc.height == 0 {
+            return Err(SharedBufferCreateError::InvalidSize(0));
+        }
+        if !SharedTextureDescriptor::SUPPORTED_FORMATS.contains(&desc.format) {
+            return Err(SharedBufferCreateError::UnsupportedFormat(desc.format));
+        }
+        let unsupported = desc.usage - SharedTextureDescriptor::SUPPORTED_USAGES;
+        if !unsupported.is_empty() {
+            return Err(SharedBufferCreateError::UnsupportedTextureUsage(
+                unsupported,
+            ));
+        }
+        match self.backend_data.as_backend() {
+            #[cfg(dx12)]
+            Backend::Dx12 => self.allocate_shared_texture_dx12(desc),
+            #[cfg(vulkan)]
+            Backend::Vulkan => self.allocate_shared_texture_vulkan(desc),
+            Backend::Staging => Err(SharedBufferCreateError::StagingUnsupported),
+        }
+    }
+
+    /// Makes wgpu's writes to `texture` visible to OIDN, like [`Device::sync_to_oidn`].
+    ///
+    /// Waits for all submitted wgpu work. The texture must be in the layout
+    /// [`SharedTexture::encode_oidn_layout`] transitions it to.
+    pub fn sync_texture_to_oidn(&self, texture: &SharedTexture) -> Result<(), SyncError> {
+        self.wgpu_device.poll(wgpu::PollType::wait_indefinitely())?;
+        texture.owner.set_oidn();
+        Ok(())
+    }
+
+    /// Makes OIDN's writes to `texture` visible to wgpu, like [`Device::sync_to_wgpu`].
+    ///
+    /// Waits for all OIDN work.
+    pub fn sync_texture_to_wgpu(&self, texture: &SharedTexture) -> Result<(), SyncError> {
+        unsafe { oidn::sys::oidnSyncDevice(self.oidn_device.raw()) };
+        texture.owner.set_wgpu();
+        Ok(())
+    }
+}
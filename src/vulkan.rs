@@ -5,167 +5,457 @@ use oidn::sys::{
     OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
 };
 
+use std::ffi::{CStr, CString};
 use std::ptr;
 use wgpu::hal::api::Vulkan;
 use wgpu::hal::{CommandEncoder, vulkan};
 use wgpu::util::align_to;
-use wgpu::{BufferDescriptor, BufferUsages, DeviceDescriptor};
+use wgpu::{BufferDescriptor, BufferUsages};
 
 // We can't rely on the windows crate existing here and this may also be either a u32 or u64.
 const ACCESS_GENERIC_ALL: vk::DWORD = 268435456;
 
+/// How memory is exported from Vulkan to OIDN.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub(crate) enum VulkanSharingMode {
+pub enum VulkanSharingMode {
+    /// An opaque Win32 handle (`VK_KHR_external_memory_win32`).
     Win32,
+    /// An opaque file descriptor (`VK_KHR_external_memory_fd`).
     Fd,
+    /// A DMA-BUF file descriptor (`VK_EXT_external_memory_dma_buf`).
     Dma,
 }
 
-impl crate::Device {
-    pub(crate) async fn new_vulkan(
-        adapter: &wgpu::Adapter,
-        desc: &DeviceDescriptor<'_>,
-    ) -> Result<(Self, wgpu::Queue), crate::DeviceCreateError> {
-        let mut win_32_handle_supported = false;
-        let mut fd_supported = false;
-        let mut dma_buf_supported = false;
+impl VulkanSharingMode {
+    /// The order sharing modes are tried in unless a [`crate::DeviceBuilder`] says otherwise.
+    pub(crate) const DEFAULT_ORDER: [VulkanSharingMode; 3] = [
+        VulkanSharingMode::Win32,
+        VulkanSharingMode::Fd,
+        VulkanSharingMode::Dma,
+    ];
+
+    /// The device extensions that must be enabled to share memory this way.
+    pub(crate) fn required_extensions(self) -> &'static [&'static CStr] {
+        match self {
+            VulkanSharingMode::Win32 => &[khr::external_memory_win32::NAME],
+            VulkanSharingMode::Fd => &[khr::external_memory_fd::NAME],
+            VulkanSharingMode::Dma => &[
+                khr::external_memory_fd::NAME,
+                ext::external_memory_dma_buf::NAME,
+            ],
+        }
+    }
+
+    fn handle_type(self) -> vk::ExternalMemoryHandleTypeFlags {
+        match self {
+            VulkanSharingMode::Win32 => vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32_KHR,
+            VulkanSharingMode::Fd => vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD_KHR,
+            VulkanSharingMode::Dma => vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT,
+        }
+    }
 
-        let adapter_vulkan_desc = {
-            // # SAFETY: the raw handle is not manually destroyed.
-            let adapter = unsafe { adapter.as_hal::<Vulkan>() };
+    fn oidn_memory_type(self) -> oidn::sys::OIDNExternalMemoryTypeFlag {
+        match self {
+            VulkanSharingMode::Win32 => {
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32
+            }
+            VulkanSharingMode::Fd => {
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD
+            }
+            VulkanSharingMode::Dma => {
+                OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF
+            }
+        }
+    }
+}
+
+/// The external memory extensions that any sharing mode uses.
+const EXTERNAL_MEMORY_EXTENSIONS: [&CStr; 3] = [
+    khr::external_memory_win32::NAME,
+    khr::external_memory_fd::NAME,
+    ext::external_memory_dma_buf::NAME,
+];
+
+/// Translates wgpu usages into the Vulkan usages wgpu would have created the buffer with.
+fn buffer_usage_flags(usage: BufferUsages) -> vk::BufferUsageFlags {
+    let mut flags = vk::BufferUsageFlags::empty();
+    if usage.intersects(BufferUsages::COPY_DST | BufferUsages::QUERY_RESOLVE) {
+        flags |= vk::BufferUsageFlags::TRANSFER_DST;
+    }
+    if usage.contains(BufferUsages::COPY_SRC) {
+        flags |= vk::BufferUsageFlags::TRANSFER_SRC;
+    }
+    if usage.contains(BufferUsages::INDEX) {
+        flags |= vk::BufferUsageFlags::INDEX_BUFFER;
+    }
+    if usage.contains(BufferUsages::VERTEX) {
+        flags |= vk::BufferUsageFlags::VERTEX_BUFFER;
+    }
+    if usage.contains(BufferUsages::UNIFORM) {
+        flags |= vk::BufferUsageFlags::UNIFORM_BUFFER;
+    }
+    // wgpu binds indirect buffers as storage to validate the arguments, and query resolve
+    // buffers to normalise timestamps
+    if usage
+        .intersects(BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::QUERY_RESOLVE)
+    {
+        flags |= vk::BufferUsageFlags::STORAGE_BUFFER;
+    }
+    if usage.contains(BufferUsages::INDIRECT) {
+        flags |= vk::BufferUsageFlags::INDIRECT_BUFFER;
+    }
+    flags
+}
+
+/// Names `object` through `VK_EXT_debug_utils`, if the instance has it enabled.
+pub(crate) fn set_object_name(device: &vulkan::Device, object: impl vk::Handle, name: &str) {
+    let instance = device.shared_instance();
+    if !instance.extensions().contains(&ext::debug_utils::NAME) {
+        return;
+    }
+    let Ok(name) = CString::new(name) else {
+        return;
+    };
+    let debug_utils = ext::debug_utils::Device::new(instance.raw_instance(), device.raw_device());
+    // Names only help debugging tools, so failing to set one is not an error.
+    let _ = unsafe {
+        debug_utils.set_debug_utils_object_name(
+            &vk::DebugUtilsObjectNameInfoEXT::default()
+                .object_handle(object)
+                .object_name(&name),
+        )
+    };
+}
+
+/// The parts of a Vulkan adapter that decide whether, and how, it can share memory with OIDN.
+pub(crate) struct VulkanAdapterSupport {
+    /// External memory extensions the physical device supports.
+    pub(crate) supported_extensions: Vec<&'static CStr>,
+    /// Sharing modes whose extensions the physical device supports.
+    pub(crate) supported_modes: Vec<VulkanSharingMode>,
+    pub(crate) api_version: u32,
+    pub(crate) device_luid: Option<[u8; vk::LUID_SIZE]>,
+    /// Only queried if the API version is at least 1.1.
+    pub(crate) device_uuid: Option<[u8; vk::UUID_SIZE]>,
+}
+
+impl VulkanAdapterSupport {
+    /// Queries `adapter` without checking whether it is usable.
+    pub(crate) fn query(adapter: &wgpu::Adapter) -> Result<Self, crate::DeviceCreateError> {
+        // # SAFETY: the raw handle is not manually destroyed.
+        let Some(adapter) = (unsafe { adapter.as_hal::<Vulkan>() }) else {
+            return Err(crate::DeviceCreateError::UnsupportedBackend(
+                adapter.get_info().backend,
+            ));
+        };
+        let supported_extensions: Vec<_> = EXTERNAL_MEMORY_EXTENSIONS
+            .into_iter()
+            .filter(|extension| {
+                adapter
+                    .physical_device_capabilities()
+                    .supports_extension(extension)
+            })
+            .collect();
+        let supported_modes = VulkanSharingMode::DEFAULT_ORDER
+            .into_iter()
+            .filter(|mode| {
+                mode.required_extensions()
+                    .iter()
+                    .all(|extension| supported_extensions.contains(extension))
+            })
+            .collect();
+
+        let api_version = unsafe {
             adapter
-                .and_then(|adapter| {
-                    win_32_handle_supported = adapter
-                        .physical_device_capabilities()
-                        .supports_extension(khr::external_memory_win32::NAME);
-                    fd_supported = adapter
-                        .physical_device_capabilities()
-                        .supports_extension(khr::external_memory_fd::NAME);
-                    dma_buf_supported = adapter
-                        .physical_device_capabilities()
-                        .supports_extension(ext::external_memory_dma_buf::NAME)
-                        && adapter
-                            .physical_device_capabilities()
-                            .supports_extension(khr::external_memory_fd::NAME);
-
-                    let any_supported =
-                        win_32_handle_supported || dma_buf_supported || fd_supported;
-
-                    // `get_physical_device_properties2` requires version >= 1.1
-                    (any_supported
-                        && unsafe {
-                            adapter
-                                .shared_instance()
-                                .raw_instance()
-                                .get_physical_device_properties(adapter.raw_physical_device())
-                        }
-                        .api_version
-                            >= vk::API_VERSION_1_1)
-                        .then_some(adapter)
-                })
-                .map(|adapter| {
-                    let mut id_properties = vk::PhysicalDeviceIDProperties::default();
-                    unsafe {
-                        adapter
-                            .shared_instance()
-                            .raw_instance()
-                            .get_physical_device_properties2(
-                                adapter.raw_physical_device(),
-                                &mut vk::PhysicalDeviceProperties2::default()
-                                    .push_next(&mut id_properties),
-                            )
-                    };
-                    id_properties
-                })
+                .shared_instance()
+                .raw_instance()
+                .get_physical_device_properties(adapter.raw_physical_device())
+        }
+        .api_version;
+        let mut support = Self {
+            supported_extensions,
+            supported_modes,
+            api_version,
+            device_luid: None,
+            device_uuid: None,
         };
-        let Some(vk_desc) = adapter_vulkan_desc else {
+
+        // `get_physical_device_properties2` requires version >= 1.1
+        if api_version >= vk::API_VERSION_1_1 {
+            let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+            unsafe {
+                adapter
+                    .shared_instance()
+                    .raw_instance()
+                    .get_physical_device_properties2(
+                        adapter.raw_physical_device(),
+                        &mut vk::PhysicalDeviceProperties2::default().push_next(&mut id_properties),
+                    )
+            };
+            support.device_luid =
+                (id_properties.device_luid_valid == vk::TRUE).then_some(id_properties.device_luid);
+            support.device_uuid = Some(id_properties.device_uuid);
+        }
+        Ok(support)
+    }
+
+    /// Queries `adapter` and fails if it can't share memory at all.
+    pub(crate) fn new(adapter: &wgpu::Adapter) -> Result<Self, crate::DeviceCreateError> {
+        let support = Self::query(adapter)?;
+        support.check()?;
+        Ok(support)
+    }
+
+    pub(crate) fn check(&self) -> Result<(), crate::DeviceCreateError> {
+        if self.supported_modes.is_empty() || self.device_uuid.is_none() {
+            trace_event!(
+                debug,
+                "No usable external memory extension (supported: {:?}, api version: {})",
+                self.supported_modes,
+                self.api_version
+            );
             return Err(crate::DeviceCreateError::MissingFeature);
-        };
-        let device = unsafe {
-            let mut dev_raw: oidn::sys::OIDNDevice = std::ptr::null_mut();
-            if dev_raw.is_null() && vk_desc.device_luid_valid == vk::TRUE {
-                dev_raw =
-                    oidn::sys::oidnNewDeviceByLUID((&vk_desc.device_luid) as *const _ as *const _)
+        }
+        Ok(())
+    }
+
+    /// Creates the OIDN device for this adapter, returning null if OIDN has none.
+    pub(crate) fn new_oidn_device(&self) -> (oidn::sys::OIDNDevice, Option<crate::DeviceIdMatch>) {
+        unsafe {
+            if let Some(luid) = &self.device_luid {
+                let dev_raw = oidn::sys::oidnNewDeviceByLUID(luid as *const _ as *const _);
+                if !dev_raw.is_null() {
+                    return (dev_raw, Some(crate::DeviceIdMatch::Luid));
+                }
             }
-            if dev_raw.is_null() {
-                dev_raw =
-                    oidn::sys::oidnNewDeviceByUUID((&vk_desc.device_uuid) as *const _ as *const _)
+            if let Some(uuid) = &self.device_uuid {
+                let dev_raw = oidn::sys::oidnNewDeviceByUUID(uuid as *const _ as *const _);
+                if !dev_raw.is_null() {
+                    return (dev_raw, Some(crate::DeviceIdMatch::Uuid));
+                }
             }
-            dev_raw
+            (ptr::null_mut(), None)
+        }
+    }
+
+    /// Picks the first sharing mode in `preference` that both the adapter and OIDN support. If
+    /// `enabled_extensions` is given only modes whose extensions are in it are considered.
+    pub(crate) fn choose_mode(
+        &self,
+        preference: &[VulkanSharingMode],
+        oidn_memory_types: oidn::sys::OIDNExternalMemoryTypeFlag,
+        enabled_extensions: Option<&[&'static CStr]>,
+    ) -> Result<VulkanSharingMode, crate::DeviceCreateError> {
+        let usable_modes: Vec<_> = preference
+            .iter()
+            .copied()
+            .filter(|mode| {
+                self.supported_modes.contains(mode)
+                    && oidn_memory_types & mode.oidn_memory_type() != 0
+            })
+            .collect();
+        trace_event!(
+            debug,
+            "OIDN memory types: {:#x}, usable sharing modes: {:?}",
+            oidn_memory_types,
+            usable_modes
+        );
+        let Some(&preferred) = usable_modes.first() else {
+            return Err(crate::DeviceCreateError::OidnImportUnsupported);
         };
-        Self::new_from_raw_oidn_adapter(device, adapter, desc, |flag| {
-            let oidn_supports_win32 =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32 != 0;
-            let oidn_supports_fd =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD != 0;
-            let oidn_supports_dma =
-                flag & OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF != 0;
-            if oidn_supports_win32 && win_32_handle_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Win32));
-            }
-            if oidn_supports_fd && fd_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Fd));
-            }
-            if oidn_supports_dma && dma_buf_supported {
-                return Some(crate::BackendData::Vulkan(VulkanSharingMode::Dma));
+        let Some(enabled_extensions) = enabled_extensions else {
+            return Ok(preferred);
+        };
+        usable_modes
+            .into_iter()
+            .find(|mode| {
+                mode.required_extensions()
+                    .iter()
+                    .all(|extension| enabled_extensions.contains(extension))
+            })
+            .ok_or(crate::DeviceCreateError::MissingDeviceExtensions(
+                preferred.required_extensions(),
+            ))
+    }
+}
+
+/// Opens a device on `adapter` like `request_device`, but with the extensions `mode` needs
+/// explicitly enabled.
+fn open_device(
+    adapter: &wgpu::Adapter,
+    desc: &wgpu::DeviceDescriptor<'_>,
+    mode: VulkanSharingMode,
+) -> Result<(wgpu::Device, wgpu::Queue), crate::DeviceCreateError> {
+    // These are the checks `request_device` does before opening the device.
+    let experimental_features = if desc.experimental_features.is_enabled() {
+        wgpu::Features::empty()
+    } else {
+        desc.required_features & wgpu::Features::all_experimental_mask()
+    };
+    let unsupported_features =
+        (desc.required_features - adapter.features()) | experimental_features;
+    if !unsupported_features.is_empty() {
+        return Err(crate::DeviceCreateError::UnsupportedFeatures(
+            unsupported_features,
+        ));
+    }
+    if !desc.required_limits.check_limits(&adapter.limits()) {
+        return Err(crate::DeviceCreateError::LimitsExceeded);
+    }
+
+    let open_device = {
+        // # SAFETY: the raw handle is not manually destroyed.
+        let Some(hal_adapter) = (unsafe { adapter.as_hal::<Vulkan>() }) else {
+            return Err(crate::DeviceCreateError::UnsupportedBackend(
+                adapter.get_info().backend,
+            ));
+        };
+        // # SAFETY: the features and limits were checked above and `VulkanAdapterSupport` only
+        // allows modes whose extensions the physical device supports.
+        unsafe {
+            hal_adapter.open_with_callback(
+                desc.required_features,
+                &desc.required_limits,
+                &desc.memory_hints,
+                Some(Box::new(|args| {
+                    for extension in mode.required_extensions() {
+                        if !args.extensions.contains(extension) {
+                            args.extensions.push(extension);
+                        }
+                    }
+                })),
+            )
+        }
+        .map_err(crate::DeviceCreateError::OpenDevice)?
+    };
+    let enabled_extensions = open_device.device.enabled_device_extensions();
+    if let Some(extension) = mode
+        .required_extensions()
+        .iter()
+        .find(|extension| !enabled_extensions.contains(extension))
+    {
+        trace_event!(warn, "Failed to enable {:?}", extension);
+        return Err(crate::DeviceCreateError::MissingFeature);
+    }
+    // # SAFETY: the device was opened from this adapter with the features and limits in `desc`.
+    Ok(unsafe { adapter.create_device_from_hal(open_device, desc) }?)
+}
+
+impl crate::Device {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(adapter = %adapter.get_info().name))
+    )]
+    pub(crate) async fn new_vulkan(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+    ) -> Result<(Self, wgpu::Queue), crate::DeviceCreateError> {
+        let (oidn_device, mode) = Self::vulkan_oidn_device(adapter, builder, None)?;
+        let (wgpu_device, queue) = open_device(adapter, &builder.desc, mode)?;
+        Ok((
+            Self {
+                wgpu_device,
+                oidn_device: oidn_device.into(),
+                queue: queue.clone(),
+                backend_data: crate::BackendData::Vulkan(mode),
+                converter: Default::default(),
+                worker: Default::default(),
+            },
+            queue,
+        ))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(adapter = %adapter.get_info().name))
+    )]
+    pub(crate) fn from_existing_vulkan(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+        wgpu_device: wgpu::Device,
+        queue: wgpu::Queue,
+    ) -> Result<Self, crate::DeviceCreateError> {
+        let enabled_extensions = {
+            // # SAFETY: the raw handles are not manually destroyed.
+            let hal_adapter = unsafe { adapter.as_hal::<Vulkan>() };
+            let hal_device = unsafe { wgpu_device.as_hal::<Vulkan>() };
+            let (Some(hal_adapter), Some(hal_device)) = (hal_adapter, hal_device) else {
+                return Err(crate::DeviceCreateError::AdapterMismatch);
+            };
+            if hal_adapter.raw_physical_device() != hal_device.raw_physical_device() {
+                return Err(crate::DeviceCreateError::AdapterMismatch);
             }
-            None
+            hal_device.enabled_device_extensions().to_vec()
+        };
+        let (oidn_device, mode) =
+            Self::vulkan_oidn_device(adapter, builder, Some(&enabled_extensions))?;
+        Ok(Self {
+            wgpu_device,
+            oidn_device: oidn_device.into(),
+            queue,
+            backend_data: crate::BackendData::Vulkan(mode),
+            converter: Default::default(),
+            worker: Default::default(),
+        })
+    }
+
+    /// Creates the OIDN device matching `adapter` and picks the sharing mode. If
+    /// `enabled_extensions` is given only modes whose extensions are in it are considered.
+    fn vulkan_oidn_device(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+        enabled_extensions: Option<&[&'static CStr]>,
+    ) -> Result<(oidn::Device, VulkanSharingMode), crate::DeviceCreateError> {
+        let support = VulkanAdapterSupport::new(adapter)?;
+        let (device, _) = support.new_oidn_device();
+        Self::oidn_device_from_raw(device, builder, |flag| {
+            support.choose_mode(&builder.vulkan_sharing_modes, flag, enabled_extensions)
         })
-        .await
     }
+
+    pub(crate) fn probe_vulkan(
+        adapter: &wgpu::Adapter,
+        builder: &crate::DeviceBuilder<'_>,
+        capabilities: &mut crate::InteropCapabilities,
+    ) -> Result<(), crate::DeviceCreateError> {
+        let support = VulkanAdapterSupport::query(adapter)?;
+        capabilities.vulkan_extensions = support.supported_extensions.clone();
+        support.check()?;
+        let (device, id_match) = support.new_oidn_device();
+        capabilities.id_match = id_match;
+        let (oidn_device, flag) = Self::oidn_device_from_raw(device, builder, Ok)?;
+        capabilities.oidn_device_type = Some(crate::OidnDeviceType::of(&oidn_device));
+        capabilities.oidn_external_memory_types = flag;
+        capabilities.vulkan_sharing_mode =
+            Some(support.choose_mode(&builder.vulkan_sharing_modes, flag, None)?);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     pub(crate) fn allocate_shared_buffers_vulkan(
         &self,
-        size: wgpu::BufferAddress,
+        desc: &crate::SharedBufferDescriptor<'_>,
     ) -> Result<crate::SharedBuffer, crate::SharedBufferCreateError> {
-        // can happen if all other backends are switched off
-        #[allow(unreachable_patterns)]
-        let data = match self.backend_data {
-            crate::BackendData::Vulkan(data) => data,
-            _ => unreachable!(),
-        };
+        let size = desc.size;
+        let mode = self.vulkan_sharing_mode();
 
         // # SAFETY: the raw handle is not manually destroyed.
         let device = unsafe { self.wgpu_device.as_hal::<Vulkan>() }.unwrap();
-        let mut win_32_funcs = None;
-
-        let mut fd_funcs = None;
-
-        let handle_ty = match data {
-            VulkanSharingMode::Win32 => {
-                win_32_funcs = Some(khr::external_memory_win32::Device::new(
-                    device.shared_instance().raw_instance(),
-                    device.raw_device(),
-                ));
-                vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32_KHR
-            }
-            VulkanSharingMode::Fd => {
-                fd_funcs = Some(khr::external_memory_fd::Device::new(
-                    device.shared_instance().raw_instance(),
-                    device.raw_device(),
-                ));
-                vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD_KHR
-            }
-            VulkanSharingMode::Dma => {
-                fd_funcs = Some(khr::external_memory_fd::Device::new(
-                    device.shared_instance().raw_instance(),
-                    device.raw_device(),
-                ));
-                vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT
-            }
-        };
 
         let mut vk_external_memory_info =
-            vk::ExternalMemoryBufferCreateInfo::default().handle_types(handle_ty);
+            vk::ExternalMemoryBufferCreateInfo::default().handle_types(mode.handle_type());
 
         let vk_info = vk::BufferCreateInfo::default()
             .size(size)
-            .usage(vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST)
+            .usage(buffer_usage_flags(desc.usage))
             // technically exclusive because cross adapter doesn't matter here
             .sharing_mode(vk::SharingMode::EXCLUSIVE)
             .push_next(&mut vk_external_memory_info);
 
-        let raw_buffer = unsafe { device.raw_device().create_buffer(&vk_info, None) }
-            .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+        let raw_buffer =
+            unsafe { device.raw_device().create_buffer(&vk_info, None) }.map_err(|err| {
+                crate::SharedBufferCreateError::backend(crate::AllocationStep::CreateBuffer, err)
+            })?;
 
         let req = unsafe {
             device
@@ -173,150 +463,394 @@ impl crate::Device {
                 .get_buffer_memory_requirements(raw_buffer)
         };
 
-        let aligned_size = align_to(size, req.alignment);
+        let memory = allocate_exportable_memory(&device, mode, &req, desc.memory_location)?;
 
-        let mem_properties = unsafe {
+        unsafe {
             device
-                .shared_instance()
-                .raw_instance()
-                .get_physical_device_memory_properties(device.raw_physical_device())
+                .raw_device()
+                .bind_buffer_memory(raw_buffer, memory, 0)
+        }
+        .map_err(|err| {
+            crate::SharedBufferCreateError::backend(crate::AllocationStep::BindMemory, err)
+        })?;
+
+        if let Some(label) = desc.label {
+            set_object_name(&device, raw_buffer, label);
+            set_object_name(&device, memory, label);
+        }
+
+        let oidn_buffer = self.export_memory_to_oidn(&device, mode, memory, size)?;
+        let buf = unsafe { vulkan::Buffer::from_raw_managed(raw_buffer, memory, 0, size) };
+        let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
+        // # SAFETY: the raw handle is not manually destroyed.
+        unsafe {
+            encoder.as_hal_mut::<Vulkan, _, _>(|encoder| {
+                encoder.unwrap().clear_buffer(&buf, 0..size);
+            })
         };
+        self.queue.submit([encoder.finish()]);
+        // # SAFETY: Just initialized buffer, created it from the same device and made with
+        // the manually mapped usages.
+        let wgpu_buffer = unsafe {
+            self.wgpu_device.create_buffer_from_hal::<Vulkan>(
+                buf,
+                &BufferDescriptor {
+                    label: desc.label,
+                    size,
+                    usage: desc.usage,
+                    mapped_at_creation: false,
+                },
+            )
+        };
+        Ok(crate::SharedBuffer {
+            allocation: crate::Allocation::Vulkan,
+            wgpu_buffer,
+            oidn_buffer,
+            owner: Default::default(),
+            device: self.clone(),
+        })
+    }
 
-        let mut idx = None;
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub(crate) fn allocate_shared_texture_vulkan(
+        &self,
+        desc: &crate::SharedTextureDescriptor<'_>,
+    ) -> Result<crate::SharedTexture, crate::SharedBufferCreateError> {
+        let mode = self.vulkan_sharing_mode();
 
-        let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        // # SAFETY: the raw handle is not manually destroyed.
+        let device = unsafe { self.wgpu_device.as_hal::<Vulkan>() }.unwrap();
+        let instance = device.shared_instance().raw_instance();
 
-        for (i, mem_ty) in mem_properties.memory_types_as_slice().iter().enumerate() {
-            let types_bits = 1 << i;
-            let is_required_memory_type = req.memory_type_bits & types_bits != 0;
-            let has_required_properties = mem_ty.property_flags & flags == flags;
-            if is_required_memory_type && has_required_properties {
-                idx = Some(i);
-                break;
-            }
+        let format = match desc.format {
+            wgpu::TextureFormat::Rgba32Float => vk::Format::R32G32B32A32_SFLOAT,
+            wgpu::TextureFormat::Rgba16Float => vk::Format::R16G16B16A16_SFLOAT,
+            _ => unreachable!("format was validated"),
+        };
+        let linear_features = unsafe {
+            instance.get_physical_device_format_properties(device.raw_physical_device(), format)
+        }
+        .linear_tiling_features;
+        let unsupported = unsupported_linear_usages(desc.usage, linear_features);
+        if !unsupported.is_empty() {
+            trace_event!(
+                warn,
+                "Linear {:?} textures only support {:?}",
+                desc.format,
+                linear_features
+            );
+            return Err(crate::SharedBufferCreateError::UnsupportedTextureUsage(
+                unsupported,
+            ));
         }
 
-        let Some(idx) = idx else {
-            return Err(crate::SharedBufferCreateError::OutOfMemory);
-        };
+        let mut vk_external_memory_info =
+            vk::ExternalMemoryImageCreateInfo::default().handle_types(mode.handle_type());
+        let vk_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: desc.width,
+                height: desc.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            // linear so that OIDN can address the texels
+            .tiling(vk::ImageTiling::LINEAR)
+            .usage(image_usage_flags(desc.usage))
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            // the texels are only addressable in `GENERAL`, which
+            // `SharedTexture::encode_oidn_layout` transitions to before OIDN uses the memory
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .push_next(&mut vk_external_memory_info);
 
-        let mut info = vk::MemoryAllocateInfo::default()
-            .allocation_size(aligned_size)
-            .memory_type_index(idx as u32);
+        let raw_image =
+            unsafe { device.raw_device().create_image(&vk_info, None) }.map_err(|err| {
+                crate::SharedBufferCreateError::backend(crate::AllocationStep::CreateBuffer, err)
+            })?;
+        let image_guard =
+            OnError::new(|| unsafe { device.raw_device().destroy_image(raw_image, None) });
 
-        let mut export_alloc_info = vk::ExportMemoryAllocateInfo::default().handle_types(handle_ty);
+        let req = unsafe { device.raw_device().get_image_memory_requirements(raw_image) };
+        let memory =
+            allocate_exportable_memory(&device, mode, &req, crate::MemoryLocation::DeviceLocal)?;
+        let memory_guard =
+            OnError::new(|| unsafe { device.raw_device().free_memory(memory, None) });
 
-        let mut win32_info;
+        unsafe { device.raw_device().bind_image_memory(raw_image, memory, 0) }.map_err(|err| {
+            crate::SharedBufferCreateError::backend(crate::AllocationStep::BindMemory, err)
+        })?;
 
-        match data {
-            VulkanSharingMode::Win32 => {
-                win32_info =
-                    vk::ExportMemoryWin32HandleInfoKHR::default().dw_access(ACCESS_GENERIC_ALL);
-                info = info.push_next(&mut win32_info);
-            }
-            VulkanSharingMode::Dma | VulkanSharingMode::Fd => {}
+        if let Some(label) = desc.label {
+            set_object_name(&device, raw_image, label);
+            set_object_name(&device, memory, label);
         }
 
-        info = info.push_next(&mut export_alloc_info);
+        let subresource_layout = unsafe {
+            device.raw_device().get_image_subresource_layout(
+                raw_image,
+                vk::ImageSubresource {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    array_layer: 0,
+                },
+            )
+        };
+        let layout = crate::SharedTextureLayout::new(
+            desc,
+            subresource_layout.offset,
+            subresource_layout.row_pitch,
+        );
+        let oidn_buffer = self.export_memory_to_oidn(
+            &device,
+            mode,
+            memory,
+            subresource_layout.offset + subresource_layout.size,
+        )?;
+        // wgpu owns the image and its memory from here on
+        memory_guard.disarm();
+        image_guard.disarm();
 
-        let memory = match unsafe { device.raw_device().allocate_memory(&info, None) } {
-            Ok(memory) => memory,
-            Err(_) => return Err(crate::SharedBufferCreateError::OutOfMemory),
+        let hal_desc = wgpu::hal::TextureDescriptor {
+            label: desc.label,
+            size: desc.size(),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: desc.format,
+            usage: wgpu::TextureUses::UNINITIALIZED,
+            memory_flags: wgpu::hal::MemoryFlags::empty(),
+            view_formats: Vec::new(),
         };
+        // # SAFETY: the image was created from `hal_desc` and wgpu takes ownership of it and its
+        // memory.
+        let texture = unsafe {
+            device.texture_from_raw(
+                raw_image,
+                &hal_desc,
+                None,
+                vulkan::TextureMemory::Dedicated(memory),
+            )
+        };
+        let wgpu_texture = unsafe {
+            self.wgpu_device
+                .create_texture_from_hal::<Vulkan>(texture, &desc.wgpu_descriptor())
+        };
+        Ok(crate::SharedTexture {
+            _allocation: crate::Allocation::Vulkan,
+            oidn_buffer,
+            wgpu_texture,
+            layout,
+            owner: Default::default(),
+            device: self.clone(),
+        })
+    }
 
-        unsafe {
-            device
-                .raw_device()
-                .bind_buffer_memory(raw_buffer, memory, 0)
+    pub(crate) fn vulkan_sharing_mode(&self) -> VulkanSharingMode {
+        // can happen if all other backends are switched off
+        #[allow(unreachable_patterns)]
+        match self.backend_data {
+            crate::BackendData::Vulkan(mode) => mode,
+            _ => unreachable!(),
         }
-        .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+    }
 
-        let oidn_buffer = match data {
+    /// Exports `memory` and imports the first `size` bytes of it into OIDN.
+    fn export_memory_to_oidn(
+        &self,
+        device: &vulkan::Device,
+        mode: VulkanSharingMode,
+        memory: vk::DeviceMemory,
+        size: vk::DeviceSize,
+    ) -> Result<oidn::Buffer, crate::SharedBufferCreateError> {
+        let instance = device.shared_instance().raw_instance();
+        let export_error =
+            |err| crate::SharedBufferCreateError::backend(crate::AllocationStep::ExportHandle, err);
+        let oidn_buffer = match mode {
             VulkanSharingMode::Win32 => unsafe {
-                let handle = win_32_funcs
-                    .as_ref()
-                    .unwrap()
+                let handle = khr::external_memory_win32::Device::new(instance, device.raw_device())
                     .get_memory_win32_handle(
                         &vk::MemoryGetWin32HandleInfoKHR::default()
                             .memory(memory)
-                            .handle_type(vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32_KHR),
+                            .handle_type(mode.handle_type()),
                     )
-                    .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+                    .map_err(export_error)?;
                 oidn::sys::oidnNewSharedBufferFromWin32Handle(
                     self.oidn_device.raw(),
-                    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_WIN32,
+                    mode.oidn_memory_type(),
                     handle as *mut _,
                     ptr::null(),
                     size as usize,
                 )
             },
-            VulkanSharingMode::Fd => unsafe {
-                let bit = fd_funcs
-                    .as_ref()
-                    .unwrap()
+            VulkanSharingMode::Fd | VulkanSharingMode::Dma => unsafe {
+                let fd = khr::external_memory_fd::Device::new(instance, device.raw_device())
                     .get_memory_fd(
                         &vk::MemoryGetFdInfoKHR::default()
                             .memory(memory)
-                            .handle_type(vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD_KHR),
+                            .handle_type(mode.handle_type()),
                     )
-                    .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
+                    .map_err(export_error)?;
                 oidn::sys::oidnNewSharedBufferFromFD(
                     self.oidn_device.raw(),
-                    OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_OPAQUE_FD,
-                    bit as _,
+                    mode.oidn_memory_type(),
+                    fd as _,
                     size as usize,
                 )
             },
-            VulkanSharingMode::Dma => {
-                let bit = unsafe {
-                    fd_funcs.as_ref().unwrap().get_memory_fd(
-                        &vk::MemoryGetFdInfoKHR::default()
-                            .memory(memory)
-                            .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT),
-                    )
-                }
-                .map_err(|_| crate::SharedBufferCreateError::OutOfMemory)?;
-                unsafe {
-                    oidn::sys::oidnNewSharedBufferFromFD(
-                        self.oidn_device.raw(),
-                        OIDNExternalMemoryTypeFlag_OIDN_EXTERNAL_MEMORY_TYPE_FLAG_DMA_BUF,
-                        bit as _,
-                        size as usize,
-                    )
-                }
-            }
         };
         if oidn_buffer.is_null() {
-            return Err(crate::SharedBufferCreateError::Oidn(
-                self.oidn_device.get_error().unwrap_err(),
-            ));
+            let err = self.oidn_device.get_error().unwrap_err();
+            trace_event!(warn, "Failed to create oidn buffer: {:?}", err);
+            return Err(crate::SharedBufferCreateError::Oidn(err));
         }
-        let buf = unsafe { vulkan::Buffer::from_raw_managed(raw_buffer, memory, 0, size) };
-        let mut encoder = self.wgpu_device.create_command_encoder(&Default::default());
-        // # SAFETY: the raw handle is not manually destroyed.
-        unsafe {
-            encoder.as_hal_mut::<Vulkan, _, _>(|encoder| {
-                encoder.unwrap().clear_buffer(&buf, 0..size);
+        Ok(unsafe { self.oidn_device.create_buffer_from_raw(oidn_buffer) })
+    }
+}
+
+/// Runs a cleanup when dropped unless disarmed, to destroy raw objects on the error paths of an
+/// allocation before wgpu owns them.
+struct OnError<F: FnOnce()>(Option<F>);
+
+impl<F: FnOnce()> OnError<F> {
+    fn new(cleanup: F) -> Self {
+        Self(Some(cleanup))
+    }
+
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl<F: FnOnce()> Drop for OnError<F> {
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.0.take() {
+            cleanup();
+        }
+    }
+}
+
+/// Allocates exportable memory for `req` in `location`.
+fn allocate_exportable_memory(
+    device: &vulkan::Device,
+    mode: VulkanSharingMode,
+    req: &vk::MemoryRequirements,
+    location: crate::MemoryLocation,
+) -> Result<vk::DeviceMemory, crate::SharedBufferCreateError> {
+    let mem_properties = unsafe {
+        device
+            .shared_instance()
+            .raw_instance()
+            .get_physical_device_memory_properties(device.raw_physical_device())
+    };
+
+    let host_visible =
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+    // tried in order, the first memory type with all the flags of an entry is used
+    let candidate_flags: &[vk::MemoryPropertyFlags] = match location {
+        crate::MemoryLocation::HostVisible => &[
+            host_visible | vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            host_visible,
+        ],
+        crate::MemoryLocation::DeviceLocal | crate::MemoryLocation::Auto => {
+            &[vk::MemoryPropertyFlags::DEVICE_LOCAL]
+        }
+    };
+
+    let idx = candidate_flags.iter().find_map(|&flags| {
+        mem_properties
+            .memory_types_as_slice()
+            .iter()
+            .enumerate()
+            .position(|(i, mem_ty)| {
+                let is_required_memory_type = req.memory_type_bits & (1 << i) != 0;
+                let has_required_properties = mem_ty.property_flags & flags == flags;
+                is_required_memory_type && has_required_properties
             })
-        };
-        self.queue.submit([encoder.finish()]);
-        // # SAFETY: Just initialized buffer, created it from the same device and made with
-        // the manually mapped usages.
-        let wgpu_buffer = unsafe {
-            self.wgpu_device.create_buffer_from_hal::<Vulkan>(
-                buf,
-                &BufferDescriptor {
-                    label: None,
-                    size,
-                    usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
-                    mapped_at_creation: false,
-                },
-            )
-        };
-        Ok(crate::SharedBuffer {
-            _allocation: crate::Allocation::Vulkan,
-            wgpu_buffer,
-            oidn_buffer: unsafe { self.oidn_device.create_buffer_from_raw(oidn_buffer) },
-        })
+    });
+
+    let Some(idx) = idx else {
+        trace_event!(
+            warn,
+            "No {:?} memory type in {:#b}",
+            location,
+            req.memory_type_bits
+        );
+        return Err(crate::SharedBufferCreateError::NoSuitableMemoryType);
+    };
+
+    let mut info = vk::MemoryAllocateInfo::default()
+        .allocation_size(align_to(req.size, req.alignment))
+        .memory_type_index(idx as u32);
+
+    let mut export_alloc_info =
+        vk::ExportMemoryAllocateInfo::default().handle_types(mode.handle_type());
+
+    let mut win32_info;
+
+    match mode {
+        VulkanSharingMode::Win32 => {
+            win32_info =
+                vk::ExportMemoryWin32HandleInfoKHR::default().dw_access(ACCESS_GENERIC_ALL);
+            info = info.push_next(&mut win32_info);
+        }
+        VulkanSharingMode::Dma | VulkanSharingMode::Fd => {}
     }
+
+    info = info.push_next(&mut export_alloc_info);
+
+    unsafe { device.raw_device().allocate_memory(&info, None) }.map_err(|err| {
+        crate::SharedBufferCreateError::backend(crate::AllocationStep::AllocateMemory, err)
+    })
+}
+
+/// Translates wgpu usages into the Vulkan usages wgpu would have created the image with.
+fn image_usage_flags(usage: wgpu::TextureUsages) -> vk::ImageUsageFlags {
+    let mut flags = vk::ImageUsageFlags::empty();
+    if usage.contains(wgpu::TextureUsages::COPY_SRC) {
+        flags |= vk::ImageUsageFlags::TRANSFER_SRC;
+    }
+    if usage.contains(wgpu::TextureUsages::COPY_DST) {
+        flags |= vk::ImageUsageFlags::TRANSFER_DST;
+    }
+    if usage.contains(wgpu::TextureUsages::TEXTURE_BINDING) {
+        flags |= vk::ImageUsageFlags::SAMPLED;
+    }
+    if usage.contains(wgpu::TextureUsages::STORAGE_BINDING) {
+        flags |= vk::ImageUsageFlags::STORAGE;
+    }
+    flags
+}
+
+/// The usages that linear images of a format with `features` can't have.
+fn unsupported_linear_usages(
+    usage: wgpu::TextureUsages,
+    features: vk::FormatFeatureFlags,
+) -> wgpu::TextureUsages {
+    [
+        (
+            wgpu::TextureUsages::COPY_SRC,
+            vk::FormatFeatureFlags::TRANSFER_SRC,
+        ),
+        (
+            wgpu::TextureUsages::COPY_DST,
+            vk::FormatFeatureFlags::TRANSFER_DST,
+        ),
+        (
+            wgpu::TextureUsages::TEXTURE_BINDING,
+            vk::FormatFeatureFlags::SAMPLED_IMAGE,
+        ),
+        (
+            wgpu::TextureUsages::STORAGE_BINDING,
+            vk::FormatFeatureFlags::STORAGE_IMAGE,
+        ),
+    ]
+    .into_iter()
+    .filter(|&(wgpu_usage, feature)| usage.contains(wgpu_usage) && !features.contains(feature))
+    .fold(
+        wgpu::TextureUsages::empty(),
+        |unsupported, (wgpu_usage, _)| unsupported | wgpu_usage,
+    )
 }